@@ -89,7 +89,50 @@ function chart_init()
             chart_update(ui.values[0], ui.values[1]);
         }
     });
+
+    //the page may come with the starting range's prices, which saves fetching them
+    if(typeof initial_prices !== 'undefined')
+    {
+        let times = [];
+        initial_prices.forEach(function(row){
+            times.push(row[0]);
+            prices.set(row[0], row[1]);
+        });
+        responses.set(starting_begin + "," + starting_end, times);
+    }
     chart_update(starting_begin, starting_end);
+    live_updates_init();
+}
+
+/* Listen for prices as the server stores them, adding each to the chart when it's showing the latest prices.
+If the connection drops, it tries again after a while.
+*/
+function live_updates_init()
+{
+    if(typeof WebSocket === 'undefined') return;
+    const scheme = (window.location.protocol === 'https:') ? 'wss://' : 'ws://';
+    const socket = new WebSocket(scheme + window.location.host + '/ws/prices');
+    socket.onmessage = function(event){
+        const update = JSON.parse(event.data);
+        if(update.pair !== 'btcusd' || prices.has(update.when)) return;
+        prices.set(update.when, update.price_cents);
+
+        //let the slider reach the new price, and draw it if the chart goes up to now
+        const slider = $("#slider");
+        if(update.when <= slider.slider("option", "max")) return;
+        slider.slider("option", "max", update.when);
+        //the end handle only moves past where it started when it's dragged all the way up
+        const showing_latest = slider.slider("values", 1) >= startup_ts;
+        if(showing_latest)
+        {
+            chart_config.data.datasets[0].data.push({x: moment.unix(update.when).utc(), y: (update.price_cents / 100.0).toFixed(2)});
+            $("#end").html(moment.utc(update.when, "X").format("YYYY-MM-DD hh:mm:ss a") + " UTC");
+            price_chart.update();
+        }
+    };
+    socket.onclose = function(){
+        setTimeout(live_updates_init, 60 * 1000);
+    };
 }
 
 /* Call each time you want to change the range displayed.
@@ -0,0 +1,1067 @@
+/*
+Computations over price series, used by the analytical API endpoints.
+
+The series these take are the (when, price) points of a resampled range, in time order,
+where every `when` is a multiple of the segment size.
+*/
+use chrono::{Datelike, NaiveDate, TimeZone};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::HashMap;
+
+use crate::trading_hours::{self, TradingWindow};
+
+/**
+Percent change of each point's price from the price a fixed window of time earlier.
+
+# Parameters
+- `series`: (when, price) points in time order, aligned to `segment_size`
+- `segment_size`: Length of each segment of the series in seconds
+- `window_secs`: How far back to compare each point to
+
+# Returns
+One (when, percent) tuple per point. The percent is None when there is no point exactly one window earlier,
+when that point's price is zero, or when the window is shorter than a segment.
+
+# Examples
+```
+use bitcoin_trend::analysis;
+let series = vec![(0, 100), (10, 110), (20, 121)];
+let returns = analysis::rolling_returns(&series, 10, 10);
+assert_eq!(returns[0], (0, None));
+assert_eq!(returns[2].1.map(|p| p.round()), Some(10.0));
+```
+*/
+pub fn rolling_returns(series: &[(u64, u64)], segment_size: u64, window_secs: u64) -> Vec<(u64, Option<f64>)>
+{
+    //align the window to the resolution of the series
+    let window_segments = (window_secs as f64 / segment_size.max(1) as f64).round() as u64;
+    let window = window_segments * segment_size;
+    let by_time: HashMap<u64, u64> = series.iter().cloned().collect();
+
+    series.iter().map(|&(when, price_cents)| {
+        let earlier = if window == 0 {None} else {when.checked_sub(window).and_then(|t| by_time.get(&t))};
+        let percent = match earlier
+        {
+            Some(&prior) if prior > 0 => Some((price_cents as f64 - prior as f64) / prior as f64 * 100.0),
+            _ => None
+        };
+        (when, percent)
+    }).collect()
+}
+
+/**
+Rescales prices so the first one equals `base` and the rest keep their proportion to it,
+so series from different eras can be compared on the same axis.
+
+# Parameters
+- `prices`: Prices in time order
+- `base`: Value the first price is scaled to, typically 100
+
+# Returns
+The scaled prices, or None if the first price is zero and so can't be scaled.
+
+# Examples
+```
+use bitcoin_trend::analysis;
+assert_eq!(analysis::index_series(&[400, 500, 300], 100.0), Some(vec![100.0, 125.0, 75.0]));
+assert_eq!(analysis::index_series(&[0, 500], 100.0), None);
+```
+*/
+pub fn index_series(prices: &[u64], base: f64) -> Option<Vec<f64>>
+{
+    let first = match prices.first()
+    {
+        None => {return Some(Vec::new());},
+        Some(0) => {return None;},
+        Some(&p) => p as f64
+    };
+    Some(prices.iter().map(|&p| p as f64 / first * base).collect())
+}
+
+/**
+Which price moves `biggest_moves` looks for.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MoveDirection
+{
+    /// Only rises
+    Up,
+    /// Only falls
+    Down,
+    /// Rises and falls alike, by size
+    Both
+}
+
+/**
+Finds the points whose price changed the most from the point before.
+
+# Parameters
+- `series`: (when, price) points in time order
+- `n`: Most moves to return
+- `direction`: Which moves to consider
+
+# Returns
+Up to `n` (when, delta, percent) tuples, largest change first, with ties in time order. The delta is in the same units as the prices.
+The percent is None when the point before had a price of zero.
+
+# Examples
+```
+use bitcoin_trend::analysis::{self, MoveDirection};
+let series = vec![(0, 100), (10, 150), (20, 140)];
+let moves = analysis::biggest_moves(&series, 1, MoveDirection::Both);
+assert_eq!(moves, vec![(10, 50, Some(50.0))]);
+```
+*/
+pub fn biggest_moves(series: &[(u64, u64)], n: usize, direction: MoveDirection) -> Vec<(u64, i64, Option<f64>)>
+{
+    let mut moves: Vec<(u64, i64, Option<f64>)> = changes(series)
+        .map(|(when, _, delta, percent)| (when, delta, percent))
+        .filter(|&(_, delta, _)| match direction
+        {
+            MoveDirection::Up => delta > 0,
+            MoveDirection::Down => delta < 0,
+            MoveDirection::Both => delta != 0
+        })
+        .collect();
+
+    //stable sort keeps equal moves in time order
+    moves.sort_by_key(|&(_, delta, _)| cmp::Reverse(delta.abs()));
+    moves.truncate(n);
+    moves
+}
+
+/**
+The change of each point of a series from the point before, as (when, price, delta, percent).
+The percent is None when the point before had a price of zero.
+*/
+fn changes(series: &[(u64, u64)]) -> impl Iterator<Item = (u64, u64, i64, Option<f64>)> + '_
+{
+    series.windows(2).map(|pair| {
+        let (_, prior) = pair[0];
+        let (when, price) = pair[1];
+        let delta = price as i64 - prior as i64;
+        let percent = if prior > 0 {Some(delta as f64 / prior as f64 * 100.0)} else {None};
+        (when, price, delta, percent)
+    })
+}
+
+/**
+A point flagged by `anomalies` for changing suspiciously much from the point before.
+*/
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct Anomaly
+{
+    /// Time of the point
+    pub when: u64,
+    /// Its price
+    pub price: u64,
+    /// Change from the price of the point before, in the same units
+    pub delta: i64,
+    /// Change in percent of the price before, or None when that was zero
+    pub percent: Option<f64>
+}
+
+/**
+Finds the points whose price changed from the point before by more than a threshold, which are likely bad data.
+A single bad price is flagged twice: once for the jump to it, and once for the jump back.
+
+# Parameters
+- `series`: (when, price) points in time order
+- `threshold_percent`: Largest change, in percent of the price before, that isn't suspicious
+
+# Returns
+The flagged points in time order. Any change from a price of zero is flagged.
+
+# Examples
+```
+use bitcoin_trend::analysis;
+let flagged = analysis::anomalies(&[(0, 100), (10, 105), (20, 200), (30, 104)], 25.0);
+assert_eq!(flagged.iter().map(|a| a.when).collect::<Vec<u64>>(), vec![20, 30]);
+```
+*/
+pub fn anomalies(series: &[(u64, u64)], threshold_percent: f64) -> Vec<Anomaly>
+{
+    changes(series)
+        .filter(|&(_, _, delta, percent)| match percent
+        {
+            Some(p) => p.abs() > threshold_percent,
+            None => delta != 0
+        })
+        .map(|(when, price, delta, percent)| Anomaly{when, price, delta, percent})
+        .collect()
+}
+
+/**
+The declines of a series from its peaks, as found by `drawdown`.
+*/
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct Drawdown
+{
+    /// Largest decline from a peak to a later trough, in percent of the peak
+    pub max_percent: f64,
+    /// When the peak before the largest decline was, or None if prices never fell
+    pub peak: Option<u64>,
+    /// When the bottom of the largest decline was, or None if prices never fell
+    pub trough: Option<u64>,
+    /// Decline of the last price from the highest price in the series, in percent of that price
+    pub current_percent: f64
+}
+
+/**
+Finds the maximum drawdown of a series, the largest decline from a peak to a later trough,
+by scanning it once while tracking the highest price so far.
+
+# Parameters
+- `series`: (when, price) points in time order
+
+# Returns
+The largest decline and the current one. Both are 0 for an empty series or one that never fell.
+Declines from a price of zero can't be measured and are ignored.
+
+# Examples
+```
+use bitcoin_trend::analysis;
+let series = vec![(0, 100), (10, 200), (20, 150), (30, 180)];
+let dd = analysis::drawdown(&series);
+assert_eq!((dd.max_percent, dd.peak, dd.trough), (25.0, Some(10), Some(20)));
+assert_eq!(dd.current_percent, 10.0);
+```
+*/
+pub fn drawdown(series: &[(u64, u64)]) -> Drawdown
+{
+    let decline = |peak: u64, price: u64| if peak > price {(peak - price) as f64 / peak as f64 * 100.0} else {0.0};
+
+    let mut result = Drawdown{max_percent: 0.0, peak: None, trough: None, current_percent: 0.0};
+    let mut running_max: Option<(u64, u64)> = None;
+    for &(when, price_cents) in series
+    {
+        match running_max
+        {
+            Some((_, max)) if max >= price_cents => {
+                let percent = decline(max, price_cents);
+                if percent > result.max_percent
+                {
+                    result.max_percent = percent;
+                    result.peak = running_max.map(|(peak_when, _)| peak_when);
+                    result.trough = Some(when);
+                }
+            },
+            _ => {running_max = Some((when, price_cents));}
+        }
+    }
+    if let (Some((_, max)), Some(&(_, last))) = (running_max, series.last())
+    {
+        result.current_percent = decline(max, last);
+    }
+    result
+}
+
+/**
+Statistics of the percent changes from each point of a series to the next, as found by `returns_stats`.
+*/
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct ReturnsStats
+{
+    /// How many returns there were
+    pub count: u64,
+    /// Average return, in percent
+    pub mean: Option<f64>,
+    /// Sample standard deviation of the returns, in percent. Needs at least 2 returns.
+    pub std_dev: Option<f64>,
+    /// Skewness of the returns (third standardized moment). Needs at least 3 returns that aren't all the same.
+    pub skewness: Option<f64>,
+    /// Excess kurtosis of the returns (fourth standardized moment minus 3, so a normal distribution has 0). Needs at least 4 returns that aren't all the same.
+    pub kurtosis: Option<f64>,
+    /// Smallest return, in percent
+    pub min: Option<f64>,
+    /// Largest return, in percent
+    pub max: Option<f64>
+}
+
+/**
+Summarizes the distribution of a series' returns, the percent change from each point to the next.
+
+# Parameters
+- `series`: (when, price) points in time order
+
+# Returns
+The statistics. Any that aren't defined for so few returns are None.
+Returns from a price of zero can't be measured and are left out.
+
+# Examples
+```
+use bitcoin_trend::analysis;
+let stats = analysis::returns_stats(&[(0, 100), (10, 110), (20, 121)]);
+assert_eq!(stats.count, 2);
+assert_eq!(stats.mean.map(|m| m.round()), Some(10.0));
+assert_eq!(stats.skewness, None);
+```
+*/
+pub fn returns_stats(series: &[(u64, u64)]) -> ReturnsStats
+{
+    let returns: Vec<f64> = series.windows(2)
+        .filter(|pair| pair[0].1 > 0)
+        .map(|pair| (pair[1].1 as f64 - pair[0].1 as f64) / pair[0].1 as f64 * 100.0)
+        .collect();
+    let n = returns.len() as f64;
+    let mut stats = ReturnsStats{count: returns.len() as u64, mean: None, std_dev: None, skewness: None, kurtosis: None, min: None, max: None};
+    if returns.is_empty() {return stats;}
+
+    let mean = returns.iter().sum::<f64>() / n;
+    //central moments
+    let moment = |k: i32| returns.iter().map(|r| (r - mean).powi(k)).sum::<f64>() / n;
+    let (m2, m3, m4) = (moment(2), moment(3), moment(4));
+
+    stats.mean = Some(mean);
+    stats.min = returns.iter().cloned().reduce(f64::min);
+    stats.max = returns.iter().cloned().reduce(f64::max);
+    if returns.len() >= 2 {stats.std_dev = Some((m2 * n / (n - 1.0)).sqrt());}
+    if m2 > 0.0
+    {
+        if returns.len() >= 3 {stats.skewness = Some(m3 / m2.powf(1.5));}
+        if returns.len() >= 4 {stats.kurtosis = Some(m4 / (m2 * m2) - 3.0);}
+    }
+    stats
+}
+
+/**
+A straight line fitted to a series by `trend_line`, with time measured in seconds since the beginning of the range.
+*/
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct TrendLine
+{
+    /// Change in price per second
+    pub slope: f64,
+    /// Price the line gives at the beginning of the range
+    pub intercept: f64,
+    /// Fraction of the variance in price that the line explains, from 0 to 1. None when the prices don't vary.
+    pub r_squared: Option<f64>,
+    /// Price the line gives at the beginning of the range, the same as `intercept`
+    pub start: f64,
+    /// Price the line gives at the end of the range
+    pub end: f64
+}
+
+/**
+Fits a line to a series by ordinary least squares, for drawing a trend over it.
+
+# Parameters
+- `series`: (when, price) points in time order
+- `begin`, `end`: The range the series covers, which the line is measured from and drawn across
+
+# Returns
+The line, or None for an empty series. A single point, or points all at the same time, give a flat line through their average price.
+
+# Examples
+```
+use bitcoin_trend::analysis;
+let line = analysis::trend_line(&[(100, 200), (110, 220), (120, 240)], 100, 130).unwrap();
+assert_eq!((line.slope, line.intercept, line.end), (2.0, 200.0, 260.0));
+```
+*/
+pub fn trend_line(series: &[(u64, u64)], begin: u64, end: u64) -> Option<TrendLine>
+{
+    if series.is_empty() {return None;}
+    let n = series.len() as f64;
+    let points: Vec<(f64, f64)> = series.iter().map(|&(when, price)| (when as f64 - begin as f64, price as f64)).collect();
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let ss_xx: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    let ss_xy: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    let ss_yy: f64 = points.iter().map(|p| (p.1 - mean_y).powi(2)).sum();
+
+    let slope = if ss_xx > 0.0 {ss_xy / ss_xx} else {0.0};
+    let intercept = mean_y - slope * mean_x;
+    let r_squared = if ss_yy > 0.0 && ss_xx > 0.0 {Some(ss_xy * ss_xy / (ss_xx * ss_yy))} else {None};
+    Some(TrendLine{slope, intercept, r_squared, start: intercept, end: intercept + slope * (end as f64 - begin as f64)})
+}
+
+/**
+One point of the Bollinger Bands found by `bollinger`, in the units of the prices.
+*/
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct BollingerPoint
+{
+    /// Time of the point
+    pub when: u64,
+    /// Moving average, or None without a full period of points
+    pub average: Option<f64>,
+    /// Average plus `k` standard deviations, or None without a full period of points
+    pub upper: Option<f64>,
+    /// Average minus `k` standard deviations, or None without a full period of points
+    pub lower: Option<f64>
+}
+
+/**
+Bollinger Bands over a series: the moving average of each point and the `period` before it, with bands `k` standard deviations above and below.
+
+# Parameters
+- `series`: (when, price) points in time order
+- `period`: How many points each average covers, including the point itself. Segments without data aren't points, so they don't count.
+- `k`: How many standard deviations the bands are from the average
+
+# Returns
+One entry per point. The average and bands are None for points with fewer than `period` points up to them, or when `period` is 0.
+The standard deviation is the population one, as is usual for these bands.
+
+# Examples
+```
+use bitcoin_trend::analysis;
+let bands = analysis::bollinger(&[(0, 10), (10, 20), (20, 30)], 2, 2.0);
+assert_eq!(bands[0].average, None);
+assert_eq!((bands[1].average, bands[1].upper, bands[1].lower), (Some(15.0), Some(25.0), Some(5.0)));
+```
+*/
+pub fn bollinger(series: &[(u64, u64)], period: usize, k: f64) -> Vec<BollingerPoint>
+{
+    series.iter().enumerate().map(|(i, &(when, _))| {
+        if period == 0 || i + 1 < period {return BollingerPoint{when, average: None, upper: None, lower: None};}
+        let window = &series[i + 1 - period..=i];
+        let n = period as f64;
+        let mean = window.iter().map(|&(_, p)| p as f64).sum::<f64>() / n;
+        let std_dev = (window.iter().map(|&(_, p)| (p as f64 - mean).powi(2)).sum::<f64>() / n).sqrt();
+        BollingerPoint{when, average: Some(mean), upper: Some(mean + k * std_dev), lower: Some(mean - k * std_dev)}
+    }).collect()
+}
+
+/**
+Relative Strength Index of each point of a series, a 0 to 100 momentum score from the average gains against the average losses.
+
+The first averages are the plain averages of the first `period` changes, and each after that is smoothed with Wilder's method:
+the previous average times `period - 1`, plus the new change, over `period`.
+
+# Parameters
+- `series`: (when, price) points in time order
+- `period`: How many changes the averages cover
+
+# Returns
+One (when, score) tuple per point. The score is None until there have been `period` changes, or when `period` is 0.
+It's 100 when there were no losses, and 50 when the price didn't move at all.
+
+# Examples
+```
+use bitcoin_trend::analysis;
+let scores = analysis::rsi(&[(0, 10), (10, 12), (20, 11)], 2);
+assert_eq!(scores[1], (10, None));
+assert_eq!(scores[2].1.map(|s| s.round()), Some(67.0));
+```
+*/
+pub fn rsi(series: &[(u64, u64)], period: usize) -> Vec<(u64, Option<f64>)>
+{
+    let score = |gain: f64, loss: f64| {
+        if loss > 0.0 {100.0 - 100.0 / (1.0 + gain / loss)} else if gain > 0.0 {100.0} else {50.0}
+    };
+    let mut scores: Vec<(u64, Option<f64>)> = series.iter().map(|&(when, _)| (when, None)).collect();
+    if period == 0 {return scores;}
+
+    let (mut avg_gain, mut avg_loss) = (0.0, 0.0);
+    for i in 1..series.len()
+    {
+        let change = series[i].1 as f64 - series[i - 1].1 as f64;
+        let (gain, loss) = (change.max(0.0), (-change).max(0.0));
+        if i <= period
+        {
+            avg_gain += gain / period as f64;
+            avg_loss += loss / period as f64;
+            if i < period {continue;}
+        }else{
+            avg_gain = (avg_gain * (period - 1) as f64 + gain) / period as f64;
+            avg_loss = (avg_loss * (period - 1) as f64 + loss) / period as f64;
+        }
+        scores[i].1 = Some(score(avg_gain, avg_loss));
+    }
+    scores
+}
+
+/**
+Calendar periods that `calendar_closes` groups points by.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CalendarUnit
+{
+    Day,
+    /// Weeks starting on Monday
+    Week,
+    Month
+}
+
+/**
+Finds the closing price of each calendar day, week or month of a series, by the calendar of a time zone rather than UTC.
+
+# Parameters
+- `series`: (when, price) points in time order
+- `by`: Which calendar periods to group the points by
+- `tz`: Time zone whose calendar sets where each period begins and ends
+
+# Returns
+One (start, price) tuple per period with any points, in time order: the Unix timestamp of local midnight at the start of the period,
+and the price of the last point in it. Where a daylight saving change skips local midnight, the period starts at its first point instead.
+
+# Examples
+```
+use bitcoin_trend::analysis::{self, CalendarUnit};
+//2020-04-09 03:00 UTC is still the evening of the 8th in New York
+let closes = analysis::calendar_closes(&[(1586401200, 689000)], CalendarUnit::Day, chrono_tz::America::New_York);
+//which started at 04:00 UTC on the 8th
+assert_eq!(closes, vec![(1586318400, 689000)]);
+```
+*/
+pub fn calendar_closes(series: &[(u64, u64)], by: CalendarUnit, tz: Tz) -> Vec<(u64, u64)>
+{
+    let mut closes: Vec<(NaiveDate, u64, u64)> = Vec::new();
+    for &(when, price) in series
+    {
+        let date = tz.timestamp(when as i64, 0).naive_local().date();
+        let period = match by
+        {
+            CalendarUnit::Day => date,
+            CalendarUnit::Week => date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64),
+            CalendarUnit::Month => NaiveDate::from_ymd(date.year(), date.month(), 1)
+        };
+        match closes.last_mut()
+        {
+            Some(last) if last.0 == period => {last.2 = price;},
+            _ => {
+                let start = tz.from_local_datetime(&period.and_hms(0, 0, 0)).earliest().map_or(when, |t| t.timestamp() as u64);
+                closes.push((period, start, price));
+            }
+        }
+    }
+    closes.into_iter().map(|(_, start, price)| (start, price)).collect()
+}
+
+/**
+How many points were stored on a day, against how many there should have been.
+*/
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct DayCompleteness
+{
+    /// Unix timestamp of midnight UTC at the start of the day
+    pub day: u64,
+    /// Points stored during the part of the day within the range
+    pub points: u64,
+    /// Points the updater would have stored there
+    pub expected: u64,
+    /// `points` as a fraction of `expected`, or None when none were expected. Above 1 when there is extra data, e.g. from an import.
+    pub fraction: Option<f64>
+}
+
+/**
+Compares the points stored on each day of a range to how many the updater is expected to store.
+
+# Parameters
+- `begin`, `end`: The range, in order
+- `counts`: (day, count) for each day that has points, with days counted from the Unix epoch, like `sql::points_per_day`
+- `interval_secs`: Seconds between the updater's fetches, which are expected at each multiple of it
+- `windows`: Trading hours, outside of which no points are expected. With none, the market is always open.
+
+# Returns
+One entry per UTC day that the range touches, in order, including days with no points.
+The first and last days only expect the fetches within the range.
+
+# Examples
+```
+use bitcoin_trend::analysis;
+//2020-04-08, a whole day with one hour missing
+let days = analysis::daily_completeness(1586304000, 1586390399, &[(18360, 23)], 3600, &[]);
+assert_eq!((days[0].points, days[0].expected), (23, 24));
+```
+*/
+pub fn daily_completeness(begin: u64, end: u64, counts: &[(u64, u64)], interval_secs: u64, windows: &[TradingWindow]) -> Vec<DayCompleteness>
+{
+    let day_secs = 86400;
+    let interval_secs = interval_secs.max(1);
+    let counts: HashMap<u64, u64> = counts.iter().cloned().collect();
+
+    (begin / day_secs..=end / day_secs).map(|day| {
+        let day_start = day * day_secs;
+        let last_slot = cmp::min(day_start.saturating_add(day_secs - 1), end);
+        //at the very end of time, the first slot may be past what a u64 holds, and then there are none
+        let expected = match cmp::max(day_start, begin).div_ceil(interval_secs).checked_mul(interval_secs)
+        {
+            None => 0,
+            Some(first_slot) => (first_slot..=last_slot).step_by(interval_secs as usize)
+                .filter(|&t| trading_hours::is_open(windows, t as i64))
+                .count() as u64
+        };
+        let points = counts.get(&day).cloned().unwrap_or(0);
+        let fraction = if expected > 0 {Some(points as f64 / expected as f64)} else {None};
+        DayCompleteness{day: day_start, points, expected, fraction}
+    }).collect()
+}
+
+/**
+Picks the points of a series that best keep its shape when drawn with fewer of them, with Largest-Triangle-Three-Buckets.
+
+The first and last points are always kept. The rest of the series is split into buckets of equal size, one per remaining point,
+and from each bucket the point is kept that makes the largest triangle with the point kept from the bucket before
+and the average of the bucket after. Peaks and dips make large triangles, so they survive where averaging would flatten them.
+
+# Parameters
+- `series`: (when, price) points in time order, which don't need to be evenly spaced
+- `n`: How many points to keep
+
+# Returns
+Indexes into `series` of the points to keep, in order. All of them if there are no more than `n`.
+
+# Examples
+```
+use bitcoin_trend::analysis;
+let series = vec![(0, 100), (1, 101), (2, 500), (3, 102), (4, 103), (5, 104)];
+assert_eq!(analysis::lttb(&series, 3), vec![0, 2, 5]);
+```
+*/
+pub fn lttb(series: &[(u64, u64)], n: usize) -> Vec<usize>
+{
+    let len = series.len();
+    if n >= len {return (0..len).collect();}
+    if n <= 2 {return [0, len - 1].iter().cloned().take(n).collect();}
+
+    let point = |i: usize| (series[i].0 as f64, series[i].1 as f64);
+    //the points between the first and last, spread over n-2 buckets
+    let every = (len - 2) as f64 / (n - 2) as f64;
+    let bucket_start = |b: usize| (b as f64 * every) as usize + 1;
+
+    let mut kept = Vec::with_capacity(n);
+    kept.push(0);
+    let mut previous = 0;
+    for b in 0..n - 2
+    {
+        //average of the next bucket, or the last point after the final bucket
+        let (next_start, next_end) = (bucket_start(b + 1), cmp::min(bucket_start(b + 2), len));
+        let (next_start, next_end) = if next_start < len - 1 {(next_start, next_end)} else {(len - 1, len)};
+        let count = (next_end - next_start) as f64;
+        let (avg_x, avg_y) = (next_start..next_end).map(point).fold((0.0, 0.0), |(x, y), (px, py)| (x + px / count, y + py / count));
+
+        let (ax, ay) = point(previous);
+        let mut best = (bucket_start(b), -1.0);
+        for i in bucket_start(b)..cmp::min(bucket_start(b + 1), len - 1)
+        {
+            let (x, y) = point(i);
+            let area = ((ax - avg_x) * (y - ay) - (ax - x) * (avg_y - ay)).abs();
+            if area > best.1 {best = (i, area);}
+        }
+        kept.push(best.0);
+        previous = best.0;
+    }
+    kept.push(len - 1);
+    kept
+}
+
+/**
+Open, high, low and close prices over a stretch of time, and how much was traded in it.
+*/
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct Candle
+{
+    /// First price
+    pub open: u64,
+    /// Highest price
+    pub high: u64,
+    /// Lowest price
+    pub low: u64,
+    /// Last price
+    pub close: u64,
+    /// Amount of the base currency traded, or None if the source didn't say
+    pub volume: Option<f64>
+}
+
+/**
+Combine candles into one per segment of a range, for charting with candlesticks.
+
+# Parameters
+- `rows`: (when, candle) rows in time order, like the stored prices
+- `segment_size`: Length of each segment in seconds, must not be zero
+- `origin`: A time a segment starts at, like 345600 for weeks starting on Monday. With 0 they start at multiples of `segment_size`.
+
+# Returns
+One (when, candle) tuple per segment that has any rows, with `when` at the start of the segment.
+It opens with the first row's open and closes with the last row's close, and spans the highest high and lowest low between.
+The volume is the sum of the volumes known, or None if none are.
+
+# Examples
+```
+use bitcoin_trend::analysis::{self, Candle};
+let rows = vec![
+    (0, Candle{open: 10, high: 12, low: 9, close: 11, volume: Some(1.0)}),
+    (5, Candle{open: 11, high: 15, low: 11, close: 14, volume: None}),
+    (10, Candle{open: 14, high: 14, low: 13, close: 13, volume: Some(2.0)})
+];
+assert_eq!(analysis::candles(&rows, 10, 0), vec![
+    (0, Candle{open: 10, high: 15, low: 9, close: 14, volume: Some(1.0)}),
+    (10, Candle{open: 14, high: 14, low: 13, close: 13, volume: Some(2.0)})
+]);
+```
+*/
+pub fn candles(rows: &[(u64, Candle)], segment_size: u64, origin: u64) -> Vec<(u64, Candle)>
+{
+    let offset = origin % segment_size;
+    let mut combined: Vec<(u64, Candle)> = Vec::new();
+    for &(when, candle) in rows
+    {
+        //rows before the first segment starting at or after the epoch go in the one at the epoch
+        let start = when.saturating_sub((when + segment_size - offset) % segment_size);
+        match combined.last_mut()
+        {
+            Some((last_start, c)) if *last_start == start => {
+                c.high = cmp::max(c.high, candle.high);
+                c.low = cmp::min(c.low, candle.low);
+                c.close = candle.close;
+                c.volume = match (c.volume, candle.volume)
+                {
+                    (Some(a), Some(b)) => Some(a + b),
+                    (a, b) => a.or(b)
+                };
+            },
+            _ => combined.push((start, candle))
+        }
+    }
+    combined
+}
+
+/*
+Test those functions which weren't able to have good tests as part of their
+example usage in the docs, but are still possible to unit-test
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // analysis::rolling_returns()
+    #[test]
+    fn rolling_return_values()
+    {
+        //daily points, 2 day window
+        let day = 86400;
+        let series = vec![(0, 1000), (day, 1100), (2*day, 1200), (3*day, 900), (5*day, 1800), (6*day, 1000)];
+        let returns = rolling_returns(&series, day, 2*day);
+
+        assert_eq!(returns.len(), 6);
+        assert_eq!(returns[0], (0, None));
+        assert_eq!(returns[1], (day, None));
+        assert_eq!(returns[2], (2*day, Some(20.0)));
+        assert_eq!(returns[3].0, 3*day);
+        assert!((returns[3].1.unwrap() - (900.0 - 1100.0) / 1100.0 * 100.0).abs() < 1e-9);
+        assert_eq!(returns[4], (5*day, Some(100.0)));
+        //the point 2 days before day 6 is missing from the series
+        assert_eq!(returns[5], (6*day, None));
+    }
+
+    // analysis::rolling_returns()
+    #[test]
+    fn rolling_return_short_window()
+    {
+        //a window shorter than half a segment rounds down to nothing to compare against
+        let series = vec![(0, 1000), (3600, 1100)];
+        assert!(rolling_returns(&series, 3600, 60).iter().all(|(_, r)| r.is_none()));
+
+        //zero prices can't be divided by
+        let series = vec![(0, 0), (3600, 1100)];
+        assert_eq!(rolling_returns(&series, 3600, 3600)[1], (3600, None));
+    }
+
+    // analysis::biggest_moves()
+    #[test]
+    fn biggest_move_ranking()
+    {
+        //deltas: +100, -300, +200, 0, -50, +200
+        let series = vec![(0, 1000), (1, 1100), (2, 800), (3, 1000), (4, 1000), (5, 950), (6, 1150)];
+
+        let both = biggest_moves(&series, 10, MoveDirection::Both);
+        let ranked: Vec<(u64, i64)> = both.iter().map(|&(when, delta, _)| (when, delta)).collect();
+        assert_eq!(ranked, vec![(2, -300), (3, 200), (6, 200), (1, 100), (5, -50)]);
+        assert_eq!(both[1].2, Some(25.0));
+
+        let up = biggest_moves(&series, 2, MoveDirection::Up);
+        assert_eq!(up.iter().map(|m| m.0).collect::<Vec<u64>>(), vec![3, 6]);
+
+        let down = biggest_moves(&series, 10, MoveDirection::Down);
+        assert_eq!(down.iter().map(|m| m.1).collect::<Vec<i64>>(), vec![-300, -50]);
+
+        assert!(biggest_moves(&series[..1], 10, MoveDirection::Both).is_empty());
+        assert_eq!(biggest_moves(&[(0, 0), (1, 100)], 1, MoveDirection::Up), vec![(1, 100, None)]);
+    }
+
+    // analysis::drawdown()
+    #[test]
+    fn drawdown_peak_then_trough()
+    {
+        //an early dip, then a run up to 2000 and a fall to 1200 before a partial recovery
+        let series = vec![(0, 1000), (1, 900), (2, 1500), (3, 2000), (4, 1600), (5, 1200), (6, 1400), (7, 1800)];
+        let dd = drawdown(&series);
+        assert_eq!(dd.max_percent, 40.0);
+        assert_eq!(dd.peak, Some(3));
+        assert_eq!(dd.trough, Some(5));
+        assert_eq!(dd.current_percent, 10.0);
+
+        //a new high after the trough ends the current drawdown but not the maximum
+        let mut recovered = series.clone();
+        recovered.push((8, 2100));
+        let dd = drawdown(&recovered);
+        assert_eq!((dd.max_percent, dd.peak, dd.trough, dd.current_percent), (40.0, Some(3), Some(5), 0.0));
+
+        let rising = drawdown(&[(0, 100), (1, 200)]);
+        assert_eq!((rising.max_percent, rising.peak, rising.trough), (0.0, None, None));
+        assert_eq!(drawdown(&[]).current_percent, 0.0);
+        assert_eq!(drawdown(&[(0, 0), (1, 0)]).max_percent, 0.0);
+    }
+
+    // analysis::trend_line()
+    #[test]
+    fn linear_fit()
+    {
+        //climbs $1.50 an hour from $6000
+        let series: Vec<(u64, u64)> = (0..48).map(|i| (1586300000 + i * 3600, 600000 + i * 150)).collect();
+        let line = trend_line(&series, 1586300000, 1586300000 + 48 * 3600).unwrap();
+        assert!((line.slope - 150.0 / 3600.0).abs() < 1e-9);
+        assert!((line.intercept - 600000.0).abs() < 1e-6);
+        assert!((line.r_squared.unwrap() - 1.0).abs() < 1e-9);
+        assert!((line.end - (600000.0 + 48.0 * 150.0)).abs() < 1e-6);
+
+        //noise lowers the fit
+        let noisy: Vec<(u64, u64)> = series.iter().enumerate().map(|(i, &(when, price))| (when, if i % 2 == 0 {price + 2000} else {price - 2000})).collect();
+        let r_squared = trend_line(&noisy, 1586300000, 1586300000 + 48 * 3600).unwrap().r_squared.unwrap();
+        assert!(r_squared > 0.0 && r_squared < 0.9);
+
+        //degenerate ranges
+        assert_eq!(trend_line(&[], 0, 100), None);
+        assert_eq!(trend_line(&[(50, 700)], 0, 100), Some(TrendLine{slope: 0.0, intercept: 700.0, r_squared: None, start: 700.0, end: 700.0}));
+        assert_eq!(trend_line(&[(0, 700), (10, 700)], 0, 100).unwrap().r_squared, None);
+    }
+
+    // analysis::bollinger()
+    #[test]
+    fn bollinger_bands()
+    {
+        let series = vec![(0, 2), (10, 4), (20, 4), (30, 4), (40, 5), (50, 5), (60, 7), (70, 9)];
+        let bands = bollinger(&series, 8, 1.5);
+        assert!(bands[..7].iter().all(|b| b.average.is_none() && b.upper.is_none() && b.lower.is_none()));
+        //mean 5, population standard deviation 2
+        assert_eq!(bands[7], BollingerPoint{when: 70, average: Some(5.0), upper: Some(8.0), lower: Some(2.0)});
+
+        //period of 3: windows (2,4,4), (4,4,4), (4,4,5)
+        let bands = bollinger(&series, 3, 2.0);
+        let sd = (8.0f64 / 9.0).sqrt();
+        assert!((bands[2].average.unwrap() - 10.0 / 3.0).abs() < 1e-9);
+        assert!((bands[2].upper.unwrap() - (10.0 / 3.0 + 2.0 * sd)).abs() < 1e-9);
+        assert!((bands[2].lower.unwrap() - (10.0 / 3.0 - 2.0 * sd)).abs() < 1e-9);
+        //no spread, so the bands meet the average
+        assert_eq!(bands[3], BollingerPoint{when: 30, average: Some(4.0), upper: Some(4.0), lower: Some(4.0)});
+
+        assert!(bollinger(&series, 0, 2.0).iter().all(|b| b.average.is_none()));
+        assert!(bollinger(&[], 3, 2.0).is_empty());
+    }
+
+    // analysis::rsi()
+    #[test]
+    fn relative_strength()
+    {
+        let close = |a: Option<f64>, b: f64| (a.unwrap() - b).abs() < 1e-9;
+
+        //changes of +2, -1, +2, -3
+        let scores = rsi(&[(0, 10), (10, 12), (20, 11), (30, 13), (40, 10)], 2);
+        assert_eq!((scores[0].1, scores[1].1), (None, None));
+        //averages 1 and 0.5
+        assert!(close(scores[2].1, 100.0 - 100.0 / 3.0));
+        //smoothed to 1.5 and 0.25
+        assert!(close(scores[3].1, 100.0 - 100.0 / 7.0));
+        //smoothed to 0.75 and 1.625
+        assert!(close(scores[4].1, 100.0 - 100.0 / (1.0 + 0.75 / 1.625)));
+        assert_eq!(scores.iter().map(|s| s.0).collect::<Vec<u64>>(), vec![0, 10, 20, 30, 40]);
+
+        //only gains, no movement at all
+        assert!(close(rsi(&[(0, 1), (1, 2), (2, 3)], 2)[2].1, 100.0));
+        assert!(close(rsi(&[(0, 5), (1, 5), (2, 5)], 2)[2].1, 50.0));
+        //not enough changes for the period
+        assert!(rsi(&[(0, 1), (1, 2), (2, 3)], 3).iter().all(|s| s.1.is_none()));
+        assert!(rsi(&[(0, 1), (1, 2)], 0).iter().all(|s| s.1.is_none()));
+        assert!(rsi(&[], 14).is_empty());
+    }
+
+    // analysis::anomalies()
+    #[test]
+    fn spike_flagged()
+    {
+        //an hour where $6890 came in as $68900, then a real 4% drop
+        let series = vec![(0, 689000), (3600, 690100), (7200, 6890000), (10800, 690500), (14400, 662900)];
+        let flagged = anomalies(&series, 20.0);
+        assert_eq!(flagged.len(), 2);
+        assert_eq!((flagged[0].when, flagged[0].price, flagged[0].delta), (7200, 6890000, 6199900));
+        assert!((flagged[0].percent.unwrap() - 6199900.0 / 690100.0 * 100.0).abs() < 1e-9);
+        assert_eq!((flagged[1].when, flagged[1].delta), (10800, -6199500));
+        assert!(flagged[1].percent.unwrap() < -89.0);
+
+        //a tighter threshold catches the drop too
+        assert_eq!(anomalies(&series, 3.0).iter().map(|a| a.when).collect::<Vec<u64>>(), vec![7200, 10800, 14400]);
+        //a change from zero can't be measured, so it's flagged
+        assert_eq!(anomalies(&[(0, 0), (1, 0), (2, 500)], 20.0).iter().map(|a| a.when).collect::<Vec<u64>>(), vec![2]);
+        assert!(anomalies(&[(0, 100)], 0.0).is_empty());
+    }
+
+    // analysis::calendar_closes()
+    #[test]
+    fn local_calendar_days()
+    {
+        let hour = 3600;
+        //2020-04-08 22:00 UTC through 2020-04-09 05:00 UTC, hourly
+        let series: Vec<(u64, u64)> = (0..8).map(|i| (1586383200 + i * hour, 1000 + i)).collect();
+
+        //all the same UTC day after midnight
+        let utc = calendar_closes(&series, CalendarUnit::Day, chrono_tz::UTC);
+        assert_eq!(utc, vec![(1586304000, 1001), (1586390400, 1007)]);
+
+        //in New York (UTC-4) the 8th runs until 04:00 UTC, so the 03:00 UTC point closes it
+        let new_york = calendar_closes(&series, CalendarUnit::Day, chrono_tz::America::New_York);
+        assert_eq!(new_york, vec![(1586318400, 1005), (1586404800, 1007)]);
+
+        //in Tokyo (UTC+9) every point is already on the 9th
+        let tokyo = calendar_closes(&series, CalendarUnit::Day, chrono_tz::Asia::Tokyo);
+        assert_eq!(tokyo, vec![(1586358000, 1007)]);
+
+        //Wednesday the 8th and Thursday the 9th are the same week, starting Monday the 6th
+        assert_eq!(calendar_closes(&series, CalendarUnit::Week, chrono_tz::America::New_York), vec![(1586145600, 1007)]);
+        //and month, starting April 1st
+        assert_eq!(calendar_closes(&series, CalendarUnit::Month, chrono_tz::America::New_York), vec![(1585713600, 1007)]);
+        assert!(calendar_closes(&[], CalendarUnit::Day, chrono_tz::UTC).is_empty());
+    }
+
+    // analysis::daily_completeness()
+    #[test]
+    fn completeness_per_day()
+    {
+        //Wednesday 2020-04-08 through Friday 2020-04-10
+        let wednesday = 1586304000;
+        let day = 86400;
+        let counts = [(wednesday / day, 24), (wednesday / day + 2, 6)];
+
+        let days = daily_completeness(wednesday, wednesday + 3*day - 1, &counts, 3600, &[]);
+        assert_eq!(days.len(), 3);
+        assert_eq!(days[0], DayCompleteness{day: wednesday, points: 24, expected: 24, fraction: Some(1.0)});
+        //a day the updater missed entirely, and a short one
+        assert_eq!(days[1], DayCompleteness{day: wednesday + day, points: 0, expected: 24, fraction: Some(0.0)});
+        assert_eq!(days[2].fraction, Some(0.25));
+
+        //a range starting at 18:00 and ending at 05:30 the next day only expects the hours within it
+        let days = daily_completeness(wednesday + 18*3600, wednesday + day + 5*3600 + 1800, &counts, 3600, &[]);
+        assert_eq!((days[0].expected, days[1].expected), (6, 6));
+
+        //no points are expected while the market is closed
+        let windows = trading_hours::parse_windows(&[String::from("mon-fri 14:30-21:00")]).unwrap();
+        let days = daily_completeness(wednesday, wednesday + 4*day - 1, &counts, 3600, &windows);
+        assert_eq!(days[0].expected, 6);
+        assert_eq!(days[3], DayCompleteness{day: wednesday + 3*day, points: 0, expected: 0, fraction: None});
+
+        //the last days there are
+        let days = daily_completeness(u64::MAX - day, u64::MAX, &[], 3600, &[]);
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[1].day, u64::MAX - u64::MAX % day);
+        assert_eq!(days[1].expected, 8);
+    }
+
+    // analysis::index_series()
+    #[test]
+    fn indexed_to_base()
+    {
+        let prices = [43900, 65850, 21950, 87800];
+        let indexed = index_series(&prices, 100.0).unwrap();
+        assert_eq!(indexed[0], 100.0);
+        //proportions between any two points are preserved
+        for (i, &p) in prices.iter().enumerate()
+        {
+            assert!((indexed[i] / indexed[1] - p as f64 / prices[1] as f64).abs() < 1e-12);
+        }
+        assert_eq!(indexed, vec![100.0, 150.0, 50.0, 200.0]);
+
+        assert_eq!(index_series(&prices, 1.0).unwrap()[3], 2.0);
+        assert_eq!(index_series(&[], 100.0), Some(vec![]));
+        assert_eq!(index_series(&[0, 100], 100.0), None);
+    }
+
+    // analysis::lttb()
+    #[test]
+    fn lttb_keeps_extremes()
+    {
+        //a noisy hourly series with one spike and one crash well inside it
+        let mut series: Vec<(u64, u64)> = (0..1000u64).map(|i| (1586300000 + i * 3600, 700000 + (i * 7919) % 3000)).collect();
+        series[313].1 = 990000;
+        series[671].1 = 410000;
+
+        for &n in &[10, 50, 100]
+        {
+            let kept = lttb(&series, n);
+            assert_eq!(kept.len(), n);
+            assert!(kept.windows(2).all(|w| w[0] < w[1]), "indexes should be in order");
+            assert_eq!((kept[0], kept[n - 1]), (0, 999));
+            assert!(kept.contains(&313), "max point dropped with n = {}", n);
+            assert!(kept.contains(&671), "min point dropped with n = {}", n);
+        }
+
+        //nothing to drop
+        assert_eq!(lttb(&series[..5], 10), vec![0, 1, 2, 3, 4]);
+        assert_eq!(lttb(&series, 2), vec![0, 999]);
+        assert!(lttb(&[], 10).is_empty());
+    }
+
+    // analysis::returns_stats()
+    #[test]
+    fn returns_distribution()
+    {
+        let close = |a: Option<f64>, b: f64| (a.unwrap() - b).abs() < 1e-9;
+
+        //returns of +10%, -10%, +10%, -10%
+        let alternating = returns_stats(&[(0, 10000), (1, 11000), (2, 9900), (3, 10890), (4, 9801)]);
+        assert_eq!(alternating.count, 4);
+        assert!(close(alternating.mean, 0.0));
+        assert!(close(alternating.std_dev, (400.0f64 / 3.0).sqrt()));
+        assert!(close(alternating.skewness, 0.0));
+        assert!(close(alternating.kurtosis, -2.0));
+        assert!(close(alternating.min, -10.0));
+        assert!(close(alternating.max, 10.0));
+
+        //returns of 0, 0, 0, +30%: mean 7.5, central moments 168.75, 2531.25 and 66445.3125
+        let jump = returns_stats(&[(0, 100), (1, 100), (2, 100), (3, 100), (4, 130)]);
+        assert!(close(jump.mean, 7.5));
+        assert!(close(jump.std_dev, 225.0f64.sqrt()));
+        assert!(close(jump.skewness, 2.0 / 3.0f64.sqrt()));
+        assert!(close(jump.kurtosis, -2.0 / 3.0));
+
+        //too short for the higher moments, or for anything
+        let short = returns_stats(&[(0, 100), (1, 110)]);
+        assert_eq!((short.count, short.std_dev, short.skewness, short.kurtosis), (1, None, None, None));
+        assert!(close(short.mean, 10.0));
+        assert_eq!(returns_stats(&[(0, 100)]), ReturnsStats{count: 0, mean: None, std_dev: None, skewness: None, kurtosis: None, min: None, max: None});
+        //flat: no spread to standardize by
+        let flat = returns_stats(&[(0, 100), (1, 100), (2, 100), (3, 100), (4, 100)]);
+        assert_eq!((flat.std_dev, flat.skewness, flat.kurtosis), (Some(0.0), None, None));
+        //a return from zero is left out
+        assert_eq!(returns_stats(&[(0, 0), (1, 100), (2, 110)]).count, 1);
+    }
+
+    // analysis::candles()
+    #[test]
+    fn candle_segments()
+    {
+        let flat = |price: u64| Candle{open: price, high: price, low: price, close: price, volume: None};
+        //rows with no candle stored are flat at their price, and a segment with no volume known has none
+        let rows = vec![(0, flat(100)), (3600, flat(90)), (7200, Candle{open: 90, high: 120, low: 80, close: 110, volume: Some(2.5)}), (10800, flat(105))];
+        assert_eq!(candles(&rows, 7200, 0), vec![
+            (0, Candle{open: 100, high: 100, low: 90, close: 90, volume: None}),
+            (7200, Candle{open: 90, high: 120, low: 80, close: 105, volume: Some(2.5)})
+        ]);
+        //segments without rows are left out
+        assert_eq!(candles(&[(0, flat(1)), (20000, flat(2))], 7200, 0), vec![(0, flat(1)), (14400, flat(2))]);
+        assert!(candles(&[], 3600, 0).is_empty());
+        //weeks from Monday 2020-04-06, so Sunday the 12th is in its week and Monday the 13th starts the next
+        let week = 7 * 86400;
+        let rows = vec![(1586131200, flat(100)), (1586692800, flat(90)), (1586736000, flat(95))];
+        assert_eq!(candles(&rows, week, 4 * 86400), vec![
+            (1586131200, Candle{open: 100, high: 100, low: 90, close: 90, volume: None}),
+            (1586736000, flat(95))
+        ]);
+    }
+}
@@ -1,17 +1,25 @@
-use actix_web::{web, App, HttpServer};
-use log::{/*error, warn,*/ info, /*debug, trace, log, Level*/};
+use actix_web::{dev::Service, dev::ServiceRequest, web, App, HttpServer};
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use log::{/*error,*/ warn, info, /*debug, trace, log, Level*/};
+use std::process;
+use std::sync::atomic::Ordering;
 use std::thread;
+use std::time::Duration;
 
+use bitcoin_trend::cli;
+use bitcoin_trend::metrics::{self, METRICS};
 use bitcoin_trend::pages;
 use bitcoin_trend::settings;
+use bitcoin_trend::sql;
 use settings::SETTINGS;
 use bitcoin_trend::updater;
 
 /**
 Main entry point.
 
-This first ensures the database is in a good state, then starts the ongoing threads for
-the database updater and the HTTP listener.
+This first ensures the database is in a good state, then starts the database updater as a task
+on the actix runtime and runs the HTTP listener, stopping the updater once the listener is done. If a subcommand was given, that runs instead.
 Note that before execution even gets here, the configuration and logger have already been set up by
 the lazy_static code in the settings module.
 
@@ -24,24 +32,160 @@ Will panic if something went wrong with ensuring correct database state on start
 #[actix_rt::main]
 async fn main() -> std::io::Result<()>
 {
+    //A subcommand runs instead of the server
+    if let Some(command) = settings::take_command()
+    {
+        //keep stdout for the results
+        log::set_max_level(log::LevelFilter::Warn);
+        match cli::run(command).await
+        {
+            Ok(output) => {print!("{}", output); return Ok(());},
+            Err(e) => {eprintln!("{}", e); process::exit(1);}
+        }
+    }
+
     info!("Starting bitcoin_trend on {}", &SETTINGS.startup.listen_addr);
 
     //Initialize the DB if necessary, bail if we couldn't
-    if !updater::db_init() {panic!("Couldn't initialize database, see log for details.");}
+    let init = match updater::db_init().await
+    {
+        Some(i) => i,
+        None => {panic!("Couldn't initialize database, see log for details.");}
+    };
+    //One record of what this boot is running
+    info!("{}", updater::startup_event(&SETTINGS, &init));
     
-    //Keep the DB updated while the app runs
-    thread::spawn(|| { updater::updater(); });
+    //Keep the DB updated while the app runs, restarting the updater if it stops
+    let (supervisor, stop_updater) = future::abortable(updater::supervise());
+    let (updater_done, updater_stopped) = oneshot::channel();
+    actix_rt::spawn(async { let _ = supervisor.await; let _ = updater_done.send(()); });
+
+    //Let operators change log verbosity without a restart: edit config/log4rs.yml then send SIGUSR1
+    #[cfg(unix)]
+    thread::spawn(|| { settings::reload_log_config_on_signal(); });
 
     //Start the HTTP server
-    HttpServer::new(|| {
+    let server = HttpServer::new(|| {
         App::new()
+            .wrap_fn(|req, srv| {
+                //answer with 503 instead of letting a slow request run on
+                let request = format!("{} {} [request {}]", req.method(), req.path(), request_id(&req));
+                pages::with_deadline(srv.call(req), SETTINGS.http.request_timeout_ms, request)
+            })
+            .wrap_fn(|req, srv| {
+                //data endpoints are turned away while in maintenance mode
+                match pages::maintenance_response(req.path(), &pages::MAINTENANCE)
+                {
+                    Some(resp) => Either::Left(future::ok(req.into_response(resp))),
+                    None => Either::Right(srv.call(req))
+                }
+            })
+            .wrap_fn(|req, srv| {
+                //count API requests by route and status for /metrics
+                let route = pages::api_route(req.method(), req.path()).map(|r| (req.method().to_string(), r));
+                let res = srv.call(req);
+                async move {
+                    let res = res.await;
+                    if let Some((method, route)) = route
+                    {
+                        let status = match &res {Ok(r) => r.status(), Err(e) => e.as_response_error().status_code()};
+                        METRICS.record_request(&method, route, status.as_u16());
+                    }
+                    res
+                }
+            })
+            .wrap_fn(|req, srv| {
+                //gzip responses at the configured level
+                let accepts_gzip = pages::compress::accepts_gzip(req.headers());
+                let res = srv.call(req);
+                async move { Ok(pages::compress::gzip_response(res.await?, accepts_gzip, SETTINGS.http.gzip_level)) }
+            })
+            .wrap_fn(|req, srv| {
+                //access log, with the real client IP when behind a trusted proxy
+                let ip = pages::client_ip(req.headers(), req.peer_addr(), SETTINGS.http.trusted_proxy);
+                info!(target: "requests", "{} {} from {} [request {}]", req.method(), req.path(), ip.map_or(String::from("unknown"), |ip| ip.to_string()), request_id(&req));
+                srv.call(req)
+            })
+            .wrap_fn(|mut req, srv| {
+                //tag the request with an ID for the logs, its queries and the response
+                let id = pages::tag_request(req.headers_mut(), SETTINGS.http.accept_request_id);
+                pages::with_request_id(srv.call(req), id)
+            })
+            .app_data(web::PathConfig::default().error_handler(pages::path_error))   // a bad URL parameter gets a JSON error like the rest
             .route("/", web::get().to(pages::index))                            // request for root: this delivers the main app page that users see
-            .route("/api/prices/{begin}/{end}", web::get().to(pages::api))     // ajax calls get recieved here, we split part of the path into args
+            .configure(pages::configure_api)                                    // everything under /api, listed by the index at /api itself
+            .route("/admin/logs", web::get().to(pages::admin::logs))           // tail of the log file, needs the admin API key
+            .route("/admin/explain/{begin}/{end}", web::get().to(pages::admin::explain))   // query plan for a range, needs the admin API key
+            .route("/admin/export.parquet", web::get().to(pages::admin::export_parquet))   // whole price history as Parquet, needs the admin API key
+            .route("/admin/validate_config", web::post().to(pages::admin::validate_config))   // check a proposed config file, needs the admin API key
+            .route("/admin/maintenance", web::post().to(pages::admin::maintenance))   // turn maintenance mode on or off, needs the admin API key
+            .route("/admin/point/{timestamp}", web::delete().to(pages::admin::delete_point))   // delete a bad price, needs the admin API key
+            .route("/admin/audit", web::post().to(pages::admin::audit))   // compare the latest prices against the source, needs the admin API key
+            .route("/ws/prices", web::get().to(pages::live::prices))            // WebSocket pushing each price as the updater stores it
+            .route("/metrics", web::get().to(metrics::serve))                   // request, updater and database counters for Prometheus
             .service(actix_files::Files::new("/static", "static").disable_content_disposition())   // serve static files from given dir
             .default_service(web::route().to(pages::notfound))                  // where to go when nothing else matches
     })
+    .disable_signals()
+    .shutdown_timeout(SETTINGS.http.shutdown_timeout_secs)
     .bind(&SETTINGS.startup.listen_addr)?
-    .run()
-    .await
+    .run();
+
+    /* On SIGTERM or SIGINT, tell the updater and the live update sockets to wind down,
+    then stop taking connections and let the open ones finish, for up to shutdown_timeout_secs.
+    */
+    let stopping = server.clone();
+    actix_rt::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutting down");
+        updater::STOP_UPDATER.store(true, Ordering::SeqCst);
+        stopping.stop(true).await;
+    });
+    let served = server.await;
+
+    //Let the updater store what it fetched before it stops, rather than leaving it mid-insert
+    info!("Stopping updater");
+    updater::STOP_UPDATER.store(true, Ordering::SeqCst);
+    let wait = Duration::from_secs(SETTINGS.http.shutdown_timeout_secs);
+    if actix_rt::time::timeout(wait, updater_stopped).await.is_err()
+    {
+        warn!("Updater didn't stop within {} seconds; stopping it where it is.", wait.as_secs());
+        stop_updater.abort();
+    }
+
+    info!("Closing database connections");
+    sql::close_pools().await;
+    served
+}
+
+/**
+Waits for SIGTERM or SIGINT, or only Ctrl-C where there are no Unix signals.
+*/
+async fn shutdown_signal()
+{
+    #[cfg(unix)]
+    {
+        use actix_rt::signal::unix::{signal, SignalKind};
+        let terminate = async {
+            match signal(SignalKind::terminate())
+            {
+                Ok(mut term) => {term.recv().await;},
+                Err(e) => {warn!("Couldn't listen for SIGTERM: {}", e); future::pending::<()>().await;}
+            }
+        };
+        future::select(Box::pin(terminate), Box::pin(actix_rt::signal::ctrl_c())).await;
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = actix_rt::signal::ctrl_c().await;
+    }
+}
+
+/**
+The ID `pages::tag_request` gave a request, for log lines about it.
+*/
+fn request_id(req: &ServiceRequest) -> &str
+{
+    req.headers().get(pages::REQUEST_ID_HEADER).and_then(|v| v.to_str().ok()).unwrap_or("-")
 }
 
@@ -0,0 +1,97 @@
+/*
+Subcommands that use the app's config and database from the command line, instead of running the server.
+*/
+use futures::Future;
+
+use crate::pages::{cents_json, csv_lines, segment_size, stored_series};
+use crate::settings::{Command, OutputFormat, SETTINGS};
+use crate::sql;
+
+/**
+Carry out a subcommand.
+
+# Returns
+What to print to stdout, or a String describing why the command failed.
+
+# Examples
+```no_run
+use bitcoin_trend::cli;
+use bitcoin_trend::settings::{Command, OutputFormat};
+# async fn f() {
+let csv = cli::run(Command::Query{begin: 1338893400, end: 1347443400, segments: 10, format: OutputFormat::Csv}).await.unwrap();
+print!("{}", csv);
+# }
+```
+*/
+pub async fn run(command: Command) -> Result<String, String>
+{
+    match command
+    {
+        Command::Query{begin, end, segments, format} => query_output(begin, end, segments, format, SETTINGS.updater.price_scale,
+            |begin, end, segment_size| sql::price_range(begin, end, segment_size, false)).await
+    }
+}
+
+/**
+Gets a range of prices and formats them for printing, for the query subcommand.
+
+# Parameters
+- `begin`, `end`: The range
+- `segments`: How many segments to resample it into
+- `format`: How to format the points
+- `price_scale`: Units per dollar the prices are stored in
+- `fetch`: Gets the rows of a range given (begin, end, segment_size), like `sql::price_range`
+
+# Returns
+The formatted points with prices in cents, or a String describing why they couldn't be gotten.
+The virtual points at the beginning and end of time are left out.
+*/
+async fn query_output<F, Fut>(begin: u64, end: u64, segments: u64, format: OutputFormat, price_scale: u64, fetch: F) -> Result<String, String>
+    where F: FnOnce(u64, u64, u64) -> Fut, Fut: Future<Output = Result<Vec<(u64,u64,bool)>,String>>
+{
+    if end < begin {return Err(String::from("begin must be <= end"));}
+    if segments == 0 {return Err(String::from("segments must be at least 1"));}
+
+    let rows = fetch(begin, end, segment_size(begin, end, segments)).await?;
+    let points = stored_series(rows).into_iter().map(|(when, price)| (when, cents_json(price as i64, price_scale)));
+    Ok(match format
+    {
+        OutputFormat::Csv => csv_lines(points).concat(),
+        OutputFormat::Json => format!("{}\n", serde_json::Value::Array(points.map(|(when, cents)| serde_json::json!([when, cents])).collect()))
+    })
+}
+
+/*
+Test those functions which weren't able to have good tests as part of their
+example usage in the docs, but are still possible to unit-test
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // query_output
+    #[actix_rt::test]
+    async fn query_printing()
+    {
+        //stands in for the database: two segments of the requested size from the beginning of the range, between the virtual points
+        let database = |begin: u64, _end: u64, segment_size: u64| async move {
+            Ok(vec![(0, 439, true), (begin, 643, false), (begin + segment_size, 689012, false), (u64::MAX - 15, 689012, true)])
+        };
+
+        let csv = query_output(1000, 2000, 10, OutputFormat::Csv, 100, database).await.unwrap();
+        assert_eq!(csv, "when,price_cents\n1000,643\n1100,689012\n");
+
+        let json = query_output(1000, 2000, 10, OutputFormat::Json, 100, database).await.unwrap();
+        assert_eq!(json, "[[1000,643],[1100,689012]]\n");
+
+        //stored in micros, printed in cents
+        let micros = query_output(1000, 2000, 10, OutputFormat::Csv, 1000000, database).await.unwrap();
+        assert_eq!(micros.lines().nth(1), Some("1000,0.0643"));
+
+        assert!(query_output(2000, 1000, 10, OutputFormat::Csv, 100, database).await.is_err());
+        assert!(query_output(1000, 2000, 0, OutputFormat::Csv, 100, database).await.is_err());
+        let failing = query_output(1000, 2000, 10, OutputFormat::Csv, 100, |_, _, _| async {Err(String::from("gone away"))}).await;
+        assert_eq!(failing, Err(String::from("gone away")));
+    }
+}
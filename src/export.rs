@@ -0,0 +1,203 @@
+/*
+Exports of the whole price history in formats meant for other tools.
+*/
+use log::{info, /*error, warn, debug, trace, log, Level*/};
+use parquet::basic::Compression;
+use parquet::column::writer::ColumnWriter;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{FileWriter, ParquetWriter, SerializedFileWriter};
+use parquet::schema::parser::parse_message_type;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::settings::{DEFAULT_PAIR, SETTINGS};
+use crate::sql;
+
+/// Rows buffered before they're written out as a row group, which bounds the memory used while exporting
+const EXPORT_BATCH_ROWS: usize = 64*1024;
+
+/// Columns of the Parquet export. Prices are in the stored units, given by the file's `price_scale` metadata.
+const PARQUET_SCHEMA: &str = "message price_history { REQUIRED INT64 when; REQUIRED INT64 price; }";
+
+/**
+Writes (when, price) rows to a Parquet file, a row group at a time.
+*/
+pub struct PriceParquetWriter<W: 'static + ParquetWriter>
+{
+    writer: SerializedFileWriter<W>,
+    batch_rows: usize,
+    when: Vec<i64>,
+    price: Vec<i64>,
+    rows: u64
+}
+
+impl<W: 'static + ParquetWriter> PriceParquetWriter<W>
+{
+    /**
+    Start a Parquet file, with its columns compressed with Snappy.
+
+    # Parameters
+    - `sink`: Where the file is written
+    - `batch_rows`: How many rows go in each row group
+    - `price_scale`: Units per dollar of the prices, recorded in the file's metadata
+
+    # Errors
+    String describing why the file couldn't be started.
+    */
+    pub fn new(sink: W, batch_rows: usize, price_scale: u64) -> Result<Self, String>
+    {
+        let schema = Arc::new(parse_message_type(PARQUET_SCHEMA).map_err(|e| format!("Bad Parquet schema: {}", e))?);
+        let props = Arc::new(WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_key_value_metadata(Some(vec![KeyValue::new(String::from("price_scale"), price_scale.to_string())]))
+            .build());
+        let writer = SerializedFileWriter::new(sink, schema, props).map_err(|e| format!("Couldn't start Parquet file: {}", e))?;
+        let batch_rows = batch_rows.max(1);
+        Ok(PriceParquetWriter{writer, batch_rows, when: Vec::with_capacity(batch_rows), price: Vec::with_capacity(batch_rows), rows: 0})
+    }
+
+    /**
+    Add a row, writing out a row group when the batch is full.
+
+    # Errors
+    String describing why the row group couldn't be written.
+    */
+    pub fn push(&mut self, when: u64, price: u64) -> Result<(), String>
+    {
+        self.when.push(when as i64);
+        self.price.push(price as i64);
+        if self.when.len() >= self.batch_rows {self.flush()?;}
+        Ok(())
+    }
+
+    /**
+    Write out the rows in the current batch as a row group.
+    */
+    fn flush(&mut self) -> Result<(), String>
+    {
+        if self.when.is_empty() {return Ok(());}
+        let err = |e: parquet::errors::ParquetError| format!("Couldn't write Parquet row group: {}", e);
+
+        let mut row_group = self.writer.next_row_group().map_err(err)?;
+        let mut columns = vec![&self.when, &self.price].into_iter();
+        while let Some(mut column) = row_group.next_column().map_err(err)?
+        {
+            let values = columns.next().ok_or_else(|| String::from("Parquet schema has more columns than the export"))?;
+            match column
+            {
+                ColumnWriter::Int64ColumnWriter(ref mut typed) => {typed.write_batch(values, None, None).map_err(err)?;},
+                _ => {return Err(String::from("Parquet schema has a column that isn't INT64"));}
+            }
+            row_group.close_column(column).map_err(err)?;
+        }
+        self.writer.close_row_group(row_group).map_err(err)?;
+
+        self.rows += self.when.len() as u64;
+        self.when.clear();
+        self.price.clear();
+        Ok(())
+    }
+
+    /**
+    Write out the last row group and the file's footer.
+
+    # Returns
+    How many rows were written.
+
+    # Errors
+    String describing why the file couldn't be finished.
+    */
+    pub fn finish(mut self) -> Result<u64, String>
+    {
+        self.flush()?;
+        self.writer.close().map_err(|e| format!("Couldn't finish Parquet file: {}", e))?;
+        Ok(self.rows)
+    }
+}
+
+/**
+Export the whole price history of the default pair as a Parquet file, streaming rows from the database so only a batch of them is held at a time.
+
+# Parameters
+- `path`: Where to write the file, replacing anything already there
+
+# Returns
+How many rows were written.
+
+# Errors
+String describing the file, database or Parquet error.
+
+# Examples
+```no_run
+use bitcoin_trend::export;
+let rows = export::price_history_parquet(std::path::Path::new("/tmp/price_history.parquet")).unwrap();
+```
+*/
+pub fn price_history_parquet(path: &Path) -> Result<u64, String>
+{
+    let sink = File::create(path).map_err(|e| format!("Couldn't create {}: {}", path.display(), e))?;
+    let mut out = PriceParquetWriter::new(sink, EXPORT_BATCH_ROWS, SETTINGS.updater.price_scale)?;
+
+    let mut db = sql::connect_read()?;
+    let query = "SELECT CAST(`when` AS UNSIGNED), CAST(`price_cents` AS UNSIGNED) FROM `price_history` WHERE `pair` = ? ORDER BY `when`";
+    sql::query_each::<(&str,),(u64,u64),_>(&mut db, query, (DEFAULT_PAIR,), "exporting price history", |(when, price)| out.push(when, price))?;
+    let rows = out.finish()?;
+
+    info!("Exported {} rows of price history as Parquet", rows);
+    Ok(rows)
+}
+
+/*
+Test those functions which weren't able to have good tests as part of their
+example usage in the docs, but are still possible to unit-test
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::file::writer::InMemoryWriteableCursor;
+    use parquet::file::serialized_reader::SliceableCursor;
+    use parquet::record::RowAccessor;
+
+    // PriceParquetWriter
+    #[test]
+    fn parquet_round_trip()
+    {
+        let rows: Vec<(u64, u64)> = (0..10).map(|i| (1586300000 + i * 3600, 689000 + i * 137)).collect();
+        let sink = InMemoryWriteableCursor::default();
+        let mut out = PriceParquetWriter::new(sink.clone(), 4, 100).unwrap();
+        for &(when, price) in &rows {out.push(when, price).unwrap();}
+        assert_eq!(out.finish().unwrap(), 10);
+
+        let reader = SerializedFileReader::new(SliceableCursor::new(sink.data())).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.file_metadata().num_rows(), 10);
+        //batches of 4, then the remainder
+        assert_eq!(metadata.num_row_groups(), 3);
+        assert_eq!(metadata.row_group(2).num_rows(), 2);
+        assert_eq!(metadata.row_group(0).column(0).compression(), Compression::SNAPPY);
+        let scale = metadata.file_metadata().key_value_metadata().as_ref().unwrap().iter().find(|kv| kv.key == "price_scale").unwrap();
+        assert_eq!(scale.value.as_deref(), Some("100"));
+
+        let read: Vec<(u64, u64)> = reader.get_row_iter(None).unwrap()
+            .map(|row| (row.get_long(0).unwrap() as u64, row.get_long(1).unwrap() as u64))
+            .collect();
+        assert_eq!(read, rows);
+    }
+
+    // PriceParquetWriter
+    #[test]
+    fn parquet_empty()
+    {
+        let sink = InMemoryWriteableCursor::default();
+        let out = PriceParquetWriter::new(sink.clone(), 4, 100).unwrap();
+        assert_eq!(out.finish().unwrap(), 0);
+
+        let reader = SerializedFileReader::new(SliceableCursor::new(sink.data())).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 0);
+        assert_eq!(reader.metadata().num_row_groups(), 0);
+    }
+}
@@ -4,7 +4,12 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde;
 
+pub mod analysis;
+pub mod cli;
+pub mod export;
+pub mod metrics;
 pub mod pages;
 pub mod settings;
 pub mod sql;
+pub mod trading_hours;
 pub mod updater;
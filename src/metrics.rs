@@ -0,0 +1,264 @@
+/*
+Counters and gauges for Prometheus, served in its text format at /metrics.
+*/
+use actix_web::{HttpResponse, http::header, http::StatusCode};
+use actix_http::ResponseBuilder;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::pages;
+use crate::settings::SETTINGS;
+use crate::updater::{FetchLatency, FETCH_LATENCY, UPDATER_STATUS};
+
+lazy_static!
+{
+    /// Counters kept by the HTTP server, the updater and the database code while the app runs
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/**
+Counters that only ever go up while the app runs, to be read by the metrics endpoint.
+
+# Examples
+```
+use bitcoin_trend::metrics::Metrics;
+let metrics = Metrics::default();
+metrics.record_request("GET", "/api/prices/{begin}/{end}", 200);
+metrics.record_fetch("bitstamp", false);
+metrics.record_connection(true);
+```
+*/
+#[derive(Debug, Default)]
+pub struct Metrics
+{
+    /// API requests answered, by method, route pattern and status code
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    /// Fetches of the latest price, by source and whether they worked
+    fetches: Mutex<BTreeMap<(String, bool), u64>>,
+    /// Connections taken from the MySQL pools
+    connections: AtomicU64,
+    /// Times a connection couldn't be taken from the MySQL pools
+    connection_errors: AtomicU64
+}
+
+impl Metrics
+{
+    /**
+    Count an answered API request.
+
+    # Parameters
+    - `method`: HTTP method of the request
+    - `route`: Pattern of the endpoint it went to, like "/api/prices/{begin}/{end}"
+    - `status`: Status code of the response
+    */
+    pub fn record_request(&self, method: &str, route: &str, status: u16)
+    {
+        if let Ok(mut requests) = self.requests.lock()
+        {
+            *requests.entry((method.to_string(), route.to_string(), status)).or_insert(0) += 1;
+        }
+    }
+
+    /**
+    Count an attempt by the updater to fetch the latest price.
+
+    # Parameters
+    - `source`: Name of the price source
+    - `success`: Whether the fetch got a price
+    */
+    pub fn record_fetch(&self, source: &str, success: bool)
+    {
+        if let Ok(mut fetches) = self.fetches.lock()
+        {
+            *fetches.entry((source.to_string(), success)).or_insert(0) += 1;
+        }
+    }
+
+    /**
+    Count an attempt to take a connection from a MySQL pool.
+    */
+    pub fn record_connection(&self, success: bool)
+    {
+        let counter = if success {&self.connections} else {&self.connection_errors};
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/**
+Readings taken when the metrics are served, rather than counted as things happen.
+*/
+#[derive(Debug, Default)]
+struct Gauges
+{
+    /// Unix timestamp of the last time the updater stored a price, if it has since startup
+    last_update: Option<u64>,
+    /// Database queries the HTTP handlers are running right now
+    queries_in_flight: usize,
+    /// How many of those may run at once, from `max_concurrent_queries`
+    query_limit: usize
+}
+
+/**
+Responds to requests for "/metrics" in the Prometheus text format.
+
+# Returns
+HttpResponse containing:
+- API requests answered, by route and status code
+- Fetches of the latest price that worked and that failed, by source
+- When the updater last stored a price, and how long its fetches from the price source are taking. These are left out until it has.
+- Connections taken from the MySQL pools and failures to get one, and how many database queries the handlers are running
+*/
+pub async fn serve() -> HttpResponse
+{
+    let query_limit = SETTINGS.http.max_concurrent_queries;
+    let gauges = Gauges{
+        last_update: UPDATER_STATUS.lock().ok().and_then(|s| s.last_success()),
+        queries_in_flight: query_limit.saturating_sub(pages::DB_PERMITS.available_permits()),
+        query_limit
+    };
+    let body = match FETCH_LATENCY.lock()
+    {
+        Ok(latency) => metrics_text(&METRICS, &latency, &gauges),
+        Err(_) => String::new()
+    };
+    ResponseBuilder::new(StatusCode::OK)
+        .set_header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(body)
+}
+
+/**
+Formats the metrics for the metrics endpoint.
+*/
+fn metrics_text(metrics: &Metrics, latency: &FetchLatency, gauges: &Gauges) -> String
+{
+    let mut text = String::new();
+
+    if let Ok(requests) = metrics.requests.lock()
+    {
+        let samples: Vec<(String, u64)> = requests.iter()
+            .map(|((method, route, status), n)| (format!("method=\"{}\",route=\"{}\",status=\"{}\"", label_value(method), label_value(route), status), *n))
+            .collect();
+        push_family(&mut text, "bitcoin_trend_api_requests_total", "API requests answered, by route and status code", "counter", &samples);
+    }
+    if let Ok(fetches) = metrics.fetches.lock()
+    {
+        for (success, name, help) in [(true, "bitcoin_trend_fetch_successes_total", "Fetches of the latest price that got one, by source"),
+                                      (false, "bitcoin_trend_fetch_failures_total", "Fetches of the latest price that failed, by source")].iter()
+        {
+            let samples: Vec<(String, u64)> = fetches.iter()
+                .filter(|((_, s), _)| s == success)
+                .map(|((source, _), n)| (format!("source=\"{}\"", label_value(source)), *n))
+                .collect();
+            push_family(&mut text, name, help, "counter", &samples);
+        }
+    }
+    if let Some(t) = gauges.last_update
+    {
+        push_family(&mut text, "bitcoin_trend_last_update_timestamp_seconds", "Unix time the updater last stored a price", "gauge", &[(String::new(), t)]);
+    }
+
+    let floats = [
+        ("bitcoin_trend_source_fetch_latency_seconds", "Time taken by the most recent fetch from the price source", latency.last()),
+        ("bitcoin_trend_source_fetch_latency_avg_seconds", "Average time taken by the recent fetches from the price source", latency.average())
+    ];
+    for (name, help, value) in floats.iter()
+    {
+        if let Some(v) = value
+        {
+            text.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n{} {}\n", name, help, name, name, v.as_secs_f64()));
+        }
+    }
+
+    push_family(&mut text, "bitcoin_trend_mysql_connections_total", "Connections taken from the MySQL pools", "counter", &[(String::new(), metrics.connections.load(Ordering::Relaxed))]);
+    push_family(&mut text, "bitcoin_trend_mysql_connection_errors_total", "Times a connection couldn't be taken from the MySQL pools", "counter", &[(String::new(), metrics.connection_errors.load(Ordering::Relaxed))]);
+    push_family(&mut text, "bitcoin_trend_db_queries_in_flight", "Database queries the HTTP handlers are running", "gauge", &[(String::new(), gauges.queries_in_flight as u64)]);
+    push_family(&mut text, "bitcoin_trend_db_queries_limit", "Database queries the HTTP handlers may run at once", "gauge", &[(String::new(), gauges.query_limit as u64)]);
+    text
+}
+
+/**
+Appends a metric with its HELP and TYPE lines, one sample per set of labels. Nothing is appended if there are no samples.
+
+# Parameters
+- `text`: Where to append it
+- `name`, `help`, `kind`: Name, description and type of the metric
+- `samples`: Labels, already formatted without the braces, and the value of each sample. Empty labels leave the braces out.
+*/
+fn push_family(text: &mut String, name: &str, help: &str, kind: &str, samples: &[(String, u64)])
+{
+    if samples.is_empty() {return;}
+    text.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+    for (labels, value) in samples
+    {
+        if labels.is_empty() {text.push_str(&format!("{} {}\n", name, value));}
+        else {text.push_str(&format!("{}{{{}}} {}\n", name, labels, value));}
+    }
+}
+
+/**
+Escapes a label value for the Prometheus text format.
+*/
+fn label_value(value: &str) -> String
+{
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // metrics_text
+    #[test]
+    fn metrics_families()
+    {
+        let metrics = Metrics::default();
+        let mut latency = FetchLatency::default();
+        let gauges = Gauges{last_update: None, queries_in_flight: 2, query_limit: 8};
+
+        //only the ones that always have a value before anything has happened
+        let text = metrics_text(&metrics, &latency, &gauges);
+        assert!(!text.contains("bitcoin_trend_api_requests_total"));
+        assert!(!text.contains("bitcoin_trend_fetch_successes_total"));
+        assert!(!text.contains("bitcoin_trend_last_update_timestamp_seconds"));
+        assert!(!text.contains("bitcoin_trend_source_fetch_latency_seconds"));
+        assert!(text.contains("# TYPE bitcoin_trend_mysql_connections_total counter\nbitcoin_trend_mysql_connections_total 0\n"));
+        assert!(text.contains("\nbitcoin_trend_db_queries_in_flight 2\n"));
+        assert!(text.contains("\nbitcoin_trend_db_queries_limit 8\n"));
+
+        metrics.record_request("GET", "/api/prices/{begin}/{end}", 200);
+        metrics.record_request("GET", "/api/prices/{begin}/{end}", 200);
+        metrics.record_request("GET", "/api/prices/{begin}/{end:\\d+}.csv", 400);
+        metrics.record_fetch("bitstamp", true);
+        metrics.record_fetch("coinbase", false);
+        metrics.record_fetch("bitstamp", false);
+        metrics.record_connection(true);
+        metrics.record_connection(false);
+        latency.record(std::time::Duration::from_millis(500));
+        let gauges = Gauges{last_update: Some(1586300000), ..gauges};
+
+        let text = metrics_text(&metrics, &latency, &gauges);
+        assert!(text.contains("# TYPE bitcoin_trend_api_requests_total counter\n"));
+        assert!(text.contains("\nbitcoin_trend_api_requests_total{method=\"GET\",route=\"/api/prices/{begin}/{end}\",status=\"200\"} 2\n"));
+        assert!(text.contains("\nbitcoin_trend_api_requests_total{method=\"GET\",route=\"/api/prices/{begin}/{end:\\\\d+}.csv\",status=\"400\"} 1\n"));
+        assert!(text.contains("\nbitcoin_trend_fetch_successes_total{source=\"bitstamp\"} 1\n"));
+        assert!(!text.contains("bitcoin_trend_fetch_successes_total{source=\"coinbase\"}"));
+        assert!(text.contains("\nbitcoin_trend_fetch_failures_total{source=\"bitstamp\"} 1\n"));
+        assert!(text.contains("\nbitcoin_trend_fetch_failures_total{source=\"coinbase\"} 1\n"));
+        assert!(text.contains("# TYPE bitcoin_trend_last_update_timestamp_seconds gauge\nbitcoin_trend_last_update_timestamp_seconds 1586300000\n"));
+        assert!(text.contains("# TYPE bitcoin_trend_source_fetch_latency_seconds gauge\n"));
+        assert!(text.contains("\nbitcoin_trend_source_fetch_latency_seconds 0.5\n"));
+        assert!(text.contains("\nbitcoin_trend_source_fetch_latency_avg_seconds 0.5\n"));
+        assert!(text.contains("\nbitcoin_trend_mysql_connections_total 1\n"));
+        assert!(text.contains("\nbitcoin_trend_mysql_connection_errors_total 1\n"));
+    }
+
+    // label_value
+    #[test]
+    fn escaped_labels()
+    {
+        assert_eq!(label_value("bitstamp"), "bitstamp");
+        assert_eq!(label_value("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+}
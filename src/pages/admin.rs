@@ -0,0 +1,497 @@
+use actix_web::{web, HttpRequest, HttpResponse, http::header, http::HeaderMap, http::StatusCode};
+use actix_files::NamedFile;
+use actix_http::ResponseBuilder;
+use log::{warn, info, /*error, debug, trace, log, Level*/};
+use serde_json::json;
+use std::cmp;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+use crate::export;
+use crate::settings::{Settings, SETTINGS};
+use crate::sql;
+use crate::updater::{self, RECENT_PRICES};
+use super::{cents_json, check_range, db_permit, json_response, segment_size, DB_PERMITS, DEFAULT_SEGMENTS, MAINTENANCE};
+
+/// How much of the log file to read at a time while looking backwards for line breaks
+const TAIL_CHUNK_BYTES: u64 = 8192;
+
+/// Most stored prices the audit endpoint will check
+const MAX_AUDIT_POINTS: u64 = 1000;
+
+/// When the audit endpoint last ran, as a unix timestamp, for its rate limit
+static LAST_AUDIT: AtomicU64 = AtomicU64::new(0);
+
+/**
+Optional query string parameters accepted by the admin endpoint "logs"
+*/
+#[derive(Deserialize)]
+pub struct LogOptions
+{
+    /// How many lines from the end of the log to return, clamped to `max_log_lines`
+    #[serde(default = "default_log_lines")]
+    pub lines: usize
+}
+
+fn default_log_lines() -> usize {100}
+
+/**
+Query string parameters accepted by the admin endpoint "maintenance"
+*/
+#[derive(Deserialize)]
+pub struct MaintenanceOptions
+{
+    /// true to start answering 503 for the data endpoints, false to go back to normal
+    pub on: bool
+}
+
+/**
+Optional query string parameters accepted by the admin endpoint "audit"
+*/
+#[derive(Deserialize)]
+pub struct AuditOptions
+{
+    /// How many of the latest stored prices to check, from 1 to 1000
+    #[serde(default = "default_audit_points")]
+    pub points: u64,
+    /// How far, as a percentage of the source's price, a stored price may be off before it's reported
+    #[serde(default = "default_audit_tolerance")]
+    pub tolerance_percent: f64
+}
+
+fn default_audit_points() -> u64 {24}
+fn default_audit_tolerance() -> f64 {1.0}
+
+/**
+Makes sure a request to an admin endpoint carries the configured API key in its X-Api-Key header.
+
+# Parameters
+- `headers`: Headers of the request
+- `api_key`: The configured key. When empty, the admin endpoints are disabled.
+
+# Returns
+Ok if the request may continue.
+
+# Errors
+The response to send instead: 404 Not Found when the admin endpoints are disabled, or 401 Unauthorized when the key is missing or wrong.
+*/
+pub fn check_api_key(headers: &HeaderMap, api_key: &str) -> Result<(), HttpResponse>
+{
+    if api_key.is_empty()
+    {
+        return Err(json_response(StatusCode::NOT_FOUND, "Admin endpoints are disabled"));
+    }
+    match headers.get("X-Api-Key").map(|v| v.as_bytes())
+    {
+        Some(given) if given == api_key.as_bytes() => Ok(()),
+        _ => Err(json_response(StatusCode::UNAUTHORIZED, "Missing or wrong API key"))
+    }
+}
+
+/**
+Responds to requests for the admin endpoint "logs"
+
+# Parameters
+- `req`: The request, whose headers must carry the API key
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse containing (if successful) the last lines of the configured log file as plain text.
+
+# Errors
+The HttpResponse can also indicate failure, when the API key doesn't check out or the log file can't be read.
+In this case the body will be JSON containing only a string describing the error.
+*/
+pub async fn logs(req: HttpRequest, options: web::Query<LogOptions>) -> HttpResponse
+{
+    if let Err(resp) = check_api_key(req.headers(), &SETTINGS.admin.api_key) {return resp;}
+
+    let lines = cmp::min(options.lines, SETTINGS.admin.max_log_lines);
+    match tail_lines(Path::new(&SETTINGS.admin.log_path), lines)
+    {
+        Err(e) => {
+            warn!("Couldn't read log file {} for the logs endpoint: {}", SETTINGS.admin.log_path, e);
+            json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Couldn't read log file: {}", e))
+        },
+        Ok(tail) => ResponseBuilder::new(StatusCode::OK)
+            .set_header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(tail.join("\n"))
+    }
+}
+
+/**
+Responds to requests for the admin endpoint "explain", which shows how the database plans to run the query the prices endpoint would run for a range.
+
+# Parameters
+- `req`: The request, whose headers must carry the API key
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+
+# Returns
+HttpResponse containing (if successful) the plan from `EXPLAIN FORMAT=JSON` as JSON.
+
+# Errors
+The HttpResponse can also indicate failure, when the API key doesn't check out, the range is invalid, or there's a database error.
+In this case the body will be JSON containing only a string describing the error.
+*/
+pub async fn explain(req: HttpRequest, range: web::Path<(u64, u64)>) -> HttpResponse
+{
+    if let Err(resp) = check_api_key(req.headers(), &SETTINGS.admin.api_key) {return resp;}
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::explain_price_range(begin, end, segment_size(begin, end, DEFAULT_SEGMENTS), false).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(plan) => json_response(StatusCode::OK, plan)
+    }
+}
+
+/**
+Responds to requests for the admin endpoint "export.parquet", a download of the whole price history as a Parquet file.
+
+# Parameters
+- `req`: The request, whose headers must carry the API key
+
+# Returns
+HttpResponse containing (if successful) the Parquet file, with columns `when` and `price` holding the stored values.
+The file's `price_scale` metadata says how many price units make a dollar.
+It's written to a temporary file first and streamed from there, so the whole history is never held in memory.
+
+# Errors
+The HttpResponse can also indicate failure, when the API key doesn't check out or there's a database error.
+In this case the body will be JSON containing only a string describing the error.
+*/
+pub async fn export_parquet(req: HttpRequest) -> HttpResponse
+{
+    if let Err(resp) = check_api_key(req.headers(), &SETTINGS.admin.api_key) {return resp;}
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    //the export reads every row, so keep it off the worker thread
+    let path = env::temp_dir().join(format!("bitcoin_trend_export_{}.parquet", Uuid::new_v4()));
+    let file = web::block(move || {
+        let opened = export::price_history_parquet(&path)
+            .and_then(|_| NamedFile::open(&path).map_err(|e| format!("Couldn't open {}: {}", path.display(), e)));
+        //the open file is all the response needs, and its data goes away once the response is done with it
+        if let Err(e) = fs::remove_file(&path) {warn!("Couldn't remove export file {}: {}", path.display(), e);}
+        opened
+    }).await;
+    let streamed = file.map_err(|e| e.to_string())
+        .and_then(|f| f.disable_content_disposition().use_etag(false).use_last_modified(false).into_response(&req).map_err(|e| e.to_string()));
+    match streamed
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Export failed: {}",e)),
+        Ok(mut resp) => {
+            let headers = resp.headers_mut();
+            headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/vnd.apache.parquet"));
+            headers.insert(header::CONTENT_DISPOSITION, header::HeaderValue::from_static("attachment; filename=\"price_history.parquet\""));
+            resp
+        }
+    }
+}
+
+/**
+Responds to requests for the admin endpoint "validate_config", which checks a proposed config file without applying it.
+
+# Parameters
+- `req`: The request, whose headers must carry the API key
+- `body`: The proposed config file, in TOML
+
+# Returns
+HttpResponse containing JSON with `valid`, and `errors`: one `{"field": ..., "error": ...}` object per problem.
+The field is null when the file couldn't be parsed at all. The status is 200 OK for a valid config and 422 Unprocessable Entity otherwise.
+
+# Errors
+The HttpResponse can also indicate failure when the API key doesn't check out, in which case the body will be JSON containing only a string describing the error.
+*/
+pub async fn validate_config(req: HttpRequest, body: String) -> HttpResponse
+{
+    if let Err(resp) = check_api_key(req.headers(), &SETTINGS.admin.api_key) {return resp;}
+    let (status, report) = validation_report(&body);
+    json_response(status, report)
+}
+
+/**
+Parses and validates a proposed config file, for the validate_config endpoint.
+
+# Returns
+The status to respond with, and the report.
+*/
+fn validation_report(toml: &str) -> (StatusCode, serde_json::Value)
+{
+    let errors: Vec<serde_json::Value> = match Settings::from_toml(toml)
+    {
+        Err(e) => vec![json!({"field": null, "error": e})],
+        Ok(settings) => settings.validate().into_iter().map(|fe| json!({"field": fe.field, "error": fe.error})).collect()
+    };
+    let status = if errors.is_empty() {StatusCode::OK} else {StatusCode::UNPROCESSABLE_ENTITY};
+    (status, json!({"valid": errors.is_empty(), "errors": errors}))
+}
+
+/**
+Responds to requests for the admin endpoint "maintenance", which turns maintenance mode on or off.
+While it's on, the data endpoints under /api answer 503 Service Unavailable, see `maintenance_response`.
+
+# Parameters
+- `req`: The request, whose headers must carry the API key
+- `options`: actix-generated struct containing the query string parameter "on"
+
+# Returns
+HttpResponse containing JSON with `maintenance`: whether maintenance mode is now on.
+
+# Errors
+The HttpResponse can also indicate failure when the API key doesn't check out, in which case the body will be JSON containing only a string describing the error.
+*/
+pub async fn maintenance(req: HttpRequest, options: web::Query<MaintenanceOptions>) -> HttpResponse
+{
+    if let Err(resp) = check_api_key(req.headers(), &SETTINGS.admin.api_key) {return resp;}
+    MAINTENANCE.store(options.on, Ordering::Relaxed);
+    info!("Maintenance mode turned {}", if options.on {"on"} else {"off"});
+    json_response(StatusCode::OK, json!({"maintenance": options.on}))
+}
+
+/**
+Responds to requests for the admin endpoint "point", which deletes the price stored for an exact time, e.g. a bad one from the source.
+The deletion is logged with the price that was removed.
+
+# Parameters
+- `req`: The request, whose headers must carry the API key
+- `when`: actix-generated tuple containing the captured parameter "timestamp"
+
+# Returns
+HttpResponse containing JSON with `deleted`: whether a price was stored for that time, and `price_cents`: what it was, or null.
+
+# Errors
+The HttpResponse can also indicate failure, when the API key doesn't check out or there's a database error.
+In this case the body will be JSON containing only a string describing the error.
+*/
+pub async fn delete_point(req: HttpRequest, when: web::Path<(u64,)>) -> HttpResponse
+{
+    if let Err(resp) = check_api_key(req.headers(), &SETTINGS.admin.api_key) {return resp;}
+    let when = when.0;
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match web::block(move || sql::connect().and_then(|mut db| sql::delete_point(&mut db, when))).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(old) => {
+            if let Some(price) = old
+            {
+                info!("Deleted the price stored for {} through the admin API; it was {}", when, price);
+                if let Ok(mut recent) = RECENT_PRICES.lock() {recent.forget(when);}
+            }
+            json_response(StatusCode::OK, json!({"deleted": old.is_some(), "price_cents": old.map(|p| cents_json(p as i64, SETTINGS.updater.price_scale))}))
+        }
+    }
+}
+
+/**
+Responds to requests for the admin endpoint "audit", which compares the latest stored prices against a fresh fetch of the source's hourly candles
+to catch values that were stored wrong. It can only run once every `audit_interval_secs`, since it makes requests to the source.
+
+# Parameters
+- `req`: The request, whose headers must carry the API key
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse containing JSON with `checked`: how many prices were compared, `unmatched`: how many the source had no candle for,
+`skipped`: how many came from sources other than Bitstamp and the backfill, and `discrepancies`: one `{"when", "stored_cents", "source_cents", "percent"}` object per price that's off by more than the tolerance.
+
+# Errors
+The HttpResponse can also indicate failure, when the API key doesn't check out, the options are invalid, it ran too recently,
+or there's a database or source error. In this case the body will be JSON containing only a string describing the error.
+Running too recently gives 429 Too Many Requests, with a Retry-After header.
+*/
+pub async fn audit(req: HttpRequest, options: web::Query<AuditOptions>) -> HttpResponse
+{
+    if let Err(resp) = check_api_key(req.headers(), &SETTINGS.admin.api_key) {return resp;}
+    let (points, tolerance_percent) = (options.points, options.tolerance_percent);
+    if points == 0 || points > MAX_AUDIT_POINTS
+    {
+        return json_response(StatusCode::BAD_REQUEST, format!("points must be between 1 and {}", MAX_AUDIT_POINTS));
+    }
+    if !tolerance_percent.is_finite() || tolerance_percent < 0.0
+    {
+        return json_response(StatusCode::BAD_REQUEST, "tolerance_percent must be a number of at least 0");
+    }
+
+    let now = chrono::offset::Utc::now().timestamp() as u64;
+    if let Err(wait) = take_turn(&LAST_AUDIT, now, SETTINGS.admin.audit_interval_secs)
+    {
+        let mut resp = json_response(StatusCode::TOO_MANY_REQUESTS, format!("The audit ran too recently, try again in {} seconds", wait));
+        resp.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(wait));
+        return resp;
+    }
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match updater::audit_latest(points, tolerance_percent).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Audit failed: {}",e)),
+        Ok(report) => {
+            let scale = SETTINGS.updater.price_scale;
+            if report.discrepancies.is_empty()
+            {
+                info!("Audit of {} stored prices found no discrepancies", report.checked);
+            }
+            else
+            {
+                warn!("Audit of {} stored prices found {} that differ from the source", report.checked, report.discrepancies.len());
+            }
+            let discrepancies: Vec<serde_json::Value> = report.discrepancies.iter().map(|d| json!({
+                "when": d.when,
+                "stored_cents": cents_json(d.stored as i64, scale),
+                "source_cents": cents_json(d.source as i64, scale),
+                "percent": d.percent
+            })).collect();
+            json_response(StatusCode::OK, json!({"checked": report.checked, "unmatched": report.unmatched, "skipped": report.skipped, "discrepancies": discrepancies}))
+        }
+    }
+}
+
+/**
+Rate limits an endpoint to one run every `interval_secs`, recording the run if it may go ahead.
+
+# Parameters
+- `last`: When the endpoint last ran, as a unix timestamp, or 0 if it hasn't
+- `now`: Current unix timestamp
+- `interval_secs`: Fewest seconds between runs
+
+# Returns
+Ok if the endpoint may run now.
+
+# Errors
+How many seconds until it may run again.
+*/
+fn take_turn(last: &AtomicU64, now: u64, interval_secs: u64) -> Result<(), u64>
+{
+    let previous = last.load(Ordering::Relaxed);
+    let next = previous.saturating_add(interval_secs);
+    if previous != 0 && now < next {return Err(next - now);}
+    //another request may have taken the turn since it was read
+    last.compare_exchange(previous, now, Ordering::Relaxed, Ordering::Relaxed).map(|_| ()).map_err(|_| interval_secs.max(1))
+}
+
+/**
+Reads the last lines of a file, working backwards from the end so a large file isn't read in full.
+
+# Parameters
+- `path`: The file
+- `n`: How many lines to return
+
+# Returns
+Up to `n` lines in file order, without their line breaks.
+
+# Errors
+Any error opening, seeking or reading the file.
+*/
+fn tail_lines(path: &Path, n: usize) -> io::Result<Vec<String>>
+{
+    let mut file = File::open(path)?;
+    let mut pos = file.metadata()?.len();
+    let mut buf: Vec<u8> = Vec::new();
+
+    //a line break before the last n lines means they're all in the buffer
+    while pos > 0 && buf.iter().filter(|&&b| b == b'\n').count() <= n
+    {
+        let chunk = cmp::min(TAIL_CHUNK_BYTES, pos);
+        pos -= chunk;
+        file.seek(SeekFrom::Start(pos))?;
+        let mut piece = vec![0; chunk as usize];
+        file.read_exact(&mut piece)?;
+        piece.extend_from_slice(&buf);
+        buf = piece;
+    }
+
+    let text = String::from_utf8_lossy(&buf);
+    let lines: Vec<&str> = text.lines().collect();
+    let start = lines.len().saturating_sub(n);
+    Ok(lines[start..].iter().map(|l| l.to_string()).collect())
+}
+
+/*
+Test those functions which weren't able to have good tests as part of their
+example usage in the docs, but are still possible to unit-test
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    // tail_lines
+    #[test]
+    fn log_tail()
+    {
+        let path = env::temp_dir().join(format!("bitcoin_trend_tail_test_{}.log", std::process::id()));
+        //long enough to take several chunks
+        let contents: String = (1..=2000).map(|i| format!("2020-04-08 INFO - line {}\n", i)).collect();
+        fs::write(&path, contents).unwrap();
+
+        assert_eq!(tail_lines(&path, 3).unwrap(), vec!["2020-04-08 INFO - line 1998", "2020-04-08 INFO - line 1999", "2020-04-08 INFO - line 2000"]);
+        let many = tail_lines(&path, 1500).unwrap();
+        assert_eq!(many.len(), 1500);
+        assert_eq!(many[0], "2020-04-08 INFO - line 501");
+        assert_eq!(tail_lines(&path, 5000).unwrap().len(), 2000);
+        assert!(tail_lines(&path, 0).unwrap().is_empty());
+
+        fs::remove_file(&path).unwrap();
+        assert!(tail_lines(&path, 3).is_err());
+    }
+
+    // check_api_key
+    #[test]
+    fn api_key_required()
+    {
+        use actix_web::test::TestRequest;
+        let with_key = TestRequest::default().header("X-Api-Key", "s3cret").to_http_request();
+        let wrong_key = TestRequest::default().header("X-Api-Key", "guess").to_http_request();
+        let no_key = TestRequest::default().to_http_request();
+
+        assert!(check_api_key(with_key.headers(), "s3cret").is_ok());
+        assert_eq!(check_api_key(wrong_key.headers(), "s3cret").unwrap_err().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(check_api_key(no_key.headers(), "s3cret").unwrap_err().status(), StatusCode::UNAUTHORIZED);
+        //no key configured disables the endpoints, whatever is sent
+        assert_eq!(check_api_key(with_key.headers(), "").unwrap_err().status(), StatusCode::NOT_FOUND);
+    }
+
+    // take_turn
+    #[test]
+    fn audit_rate_limit()
+    {
+        let last = AtomicU64::new(0);
+        let now = 1586304000;
+        assert_eq!(take_turn(&last, now, 600), Ok(()));
+        assert_eq!(take_turn(&last, now + 1, 600), Err(599));
+        assert_eq!(take_turn(&last, now + 599, 600), Err(1));
+        assert_eq!(take_turn(&last, now + 600, 600), Ok(()));
+        assert_eq!(last.load(Ordering::Relaxed), now + 600);
+
+        //no interval never limits
+        assert_eq!(take_turn(&last, now + 600, 0), Ok(()));
+    }
+
+    // validation_report
+    #[test]
+    fn config_validation()
+    {
+        let (status, report) = validation_report("[startup]\nlisten_addr = \"127.0.0.1:4000\"\n[updater]\ntrading_hours = [\"mon-fri 14:30-21:00\"]\n");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report, json!({"valid": true, "errors": []}));
+
+        let (status, report) = validation_report("[startup]\nlisten_addr = \"127.0.0.1\"\n[http]\ngzip_level = 12\n[updater]\ntrading_hours = [\"weekdays 9-5\"]\n");
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(report["valid"], false);
+        let fields: Vec<&str> = report["errors"].as_array().unwrap().iter().map(|e| e["field"].as_str().unwrap()).collect();
+        assert_eq!(fields, vec!["startup.listen_addr", "http.gzip_level", "updater.trading_hours"]);
+
+        //not even TOML
+        let (status, report) = validation_report("[http\ngzip_level = ");
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(report["errors"][0]["field"].is_null());
+        assert!(report["errors"][0]["error"].as_str().unwrap().starts_with("Couldn't parse config"));
+    }
+}
@@ -0,0 +1,137 @@
+use actix_http::body::{Body, ResponseBody};
+use actix_web::{dev::ServiceResponse, http::header, http::HeaderMap, http::HeaderValue};
+use flate2::{write::GzEncoder, Compression};
+use log::{warn, /*error, info, debug, trace, log, Level*/};
+use std::io::prelude::*;
+
+/// Most levels flate2 understands; anything higher is treated as this
+const MAX_GZIP_LEVEL: u32 = 9;
+
+/**
+Whether a request's Accept-Encoding header allows a gzipped response.
+
+# Examples
+```
+use actix_web::test::TestRequest;
+use bitcoin_trend::pages::compress;
+let req = TestRequest::default().header("Accept-Encoding", "br, gzip;q=0.8").to_http_request();
+assert!(compress::accepts_gzip(req.headers()));
+let req = TestRequest::default().header("Accept-Encoding", "gzip;q=0").to_http_request();
+assert!(!compress::accepts_gzip(req.headers()));
+```
+*/
+pub fn accepts_gzip(headers: &HeaderMap) -> bool
+{
+    headers.get_all(header::ACCEPT_ENCODING)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or("");
+            let refused = parts.any(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+/**
+Gzip some data.
+
+# Parameters
+- `data`: What to compress
+- `level`: From 1 (fastest) to 9 (smallest)
+
+# Returns
+The gzip stream, or None if compressing failed.
+*/
+pub fn gzip(data: &[u8], level: u32) -> Option<Vec<u8>>
+{
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level.min(MAX_GZIP_LEVEL)));
+    match encoder.write_all(data).and_then(|_| encoder.finish())
+    {
+        Ok(compressed) => Some(compressed),
+        Err(e) => {warn!("Couldn't gzip a response, sending it uncompressed: {}", e); None}
+    }
+}
+
+/**
+Gzips a response at the configured level, in place of actix's Compress middleware, which always uses its own level.
+
+Only responses whose whole body is already in memory are compressed, so streamed responses
+like NDJSON prices and static files are passed through untouched, as are responses that already have an encoding.
+
+# Parameters
+- `res`: The response from the rest of the app
+- `accepts_gzip`: Whether the request allows a gzipped response, from `accepts_gzip`
+- `level`: As configured by `gzip_level`. 0 sends every response uncompressed.
+
+# Returns
+The response, compressed if it should be.
+*/
+pub fn gzip_response(mut res: ServiceResponse<Body>, accepts_gzip: bool, level: u32) -> ServiceResponse<Body>
+{
+    if !accepts_gzip || level == 0 || res.headers().contains_key(header::CONTENT_ENCODING) {return res;}
+
+    let compressed = match res.response().body()
+    {
+        ResponseBody::Body(Body::Bytes(bytes)) | ResponseBody::Other(Body::Bytes(bytes)) => match gzip(bytes, level)
+        {
+            Some(c) => c,
+            None => {return res;}
+        },
+        _ => {return res;}
+    };
+
+    let headers = res.headers_mut();
+    headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    headers.remove(header::CONTENT_LENGTH);
+    res.map_body(|_, _| ResponseBody::Body(Body::from(compressed)))
+}
+
+/*
+Test those functions which weren't able to have good tests as part of their
+example usage in the docs, but are still possible to unit-test
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use actix_web::test::TestRequest;
+    use actix_web::HttpResponse;
+    use flate2::read::GzDecoder;
+
+    // gzip
+    #[test]
+    fn gzip_levels()
+    {
+        let payload: String = (0..2000).map(|i| format!("{{\"when\":{},\"price_cents\":{}}},", 1586300000 + i * 3600, 689000 + (i * 7919) % 5000)).collect();
+        let fast = gzip(payload.as_bytes(), 1).unwrap();
+        let small = gzip(payload.as_bytes(), 9).unwrap();
+        assert!(small.len() < fast.len(), "level 9 gave {} bytes, level 1 gave {}", small.len(), fast.len());
+        assert!(fast.len() < payload.len());
+
+        let mut unzipped = String::new();
+        GzDecoder::new(&small[..]).read_to_string(&mut unzipped).unwrap();
+        assert_eq!(unzipped, payload);
+    }
+
+    // gzip_response
+    #[test]
+    fn gzip_only_when_accepted()
+    {
+        let body = "[".repeat(1000);
+        let response = || TestRequest::default().to_srv_response(HttpResponse::Ok().body(body.clone()));
+
+        let zipped = gzip_response(response(), true, 6);
+        assert_eq!(zipped.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(zipped.headers().get(header::VARY).unwrap(), "accept-encoding");
+        match zipped.response().body()
+        {
+            ResponseBody::Body(Body::Bytes(b)) => {assert!(b.len() < body.len());},
+            _ => panic!("compressed body should be in memory")
+        }
+
+        assert!(!gzip_response(response(), false, 6).headers().contains_key(header::CONTENT_ENCODING));
+        assert!(!gzip_response(response(), true, 0).headers().contains_key(header::CONTENT_ENCODING));
+    }
+}
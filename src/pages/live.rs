@@ -0,0 +1,257 @@
+/*
+Live price updates over a WebSocket, pushed to each client as the updater stores them.
+*/
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use log::{debug, /*error, warn, info, trace, log, Level*/};
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{Receiver, RecvError};
+
+use crate::settings::SETTINGS;
+use crate::updater::{PriceUpdate, PRICE_UPDATES, STOP_UPDATER};
+use super::cents_json;
+
+/// Clients connected to the live updates endpoint, including any being turned away
+static SUBSCRIBERS: AtomicUsize = AtomicUsize::new(0);
+
+/// How often each client is pinged to check it's still there
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a client may go without answering before it's disconnected
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// How often each socket checks whether the app is shutting down
+const STOP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+Responds to requests for "/ws/prices" by opening a WebSocket that gets a message each time the updater stores a price.
+
+Each message is a JSON object with the `pair`, the time it's for as `when`, and `price_cents`, e.g.
+`{"pair":"btcusd","when":1586304000,"price_cents":682345}`. Messages from the client are ignored.
+
+At most `max_subscribers` clients are connected at once. Any more are sent a close frame with code 1013 (try again later) right away.
+When the app is shutting down, each socket is closed with code 1001 (going away).
+
+# Errors
+The handshake fails if the request isn't a WebSocket upgrade.
+*/
+pub async fn prices(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error>
+{
+    ws::start(PriceSocket{
+        updates: Some(PRICE_UPDATES.subscribe()),
+        price_scale: SETTINGS.updater.price_scale,
+        subscribers: &SUBSCRIBERS,
+        max_subscribers: SETTINGS.http.max_subscribers,
+        stop: &STOP_UPDATER,
+        last_heard: Instant::now()
+    }, &req, stream)
+}
+
+/**
+One client's WebSocket.
+*/
+struct PriceSocket
+{
+    /// Where the updates come from, until the socket starts listening to it
+    updates: Option<Receiver<PriceUpdate>>,
+    /// Units per dollar the prices are stored in
+    price_scale: u64,
+    /// Count of the open sockets, this one included once it has started
+    subscribers: &'static AtomicUsize,
+    /// Most sockets that may be open at once
+    max_subscribers: usize,
+    /// Set when the app is shutting down
+    stop: &'static AtomicBool,
+    /// When the client last sent anything
+    last_heard: Instant
+}
+
+impl Actor for PriceSocket
+{
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context)
+    {
+        //counted before checking, so two clients arriving together can't both take the last place
+        if self.subscribers.fetch_add(1, Ordering::SeqCst) >= self.max_subscribers
+        {
+            debug!("Turning away a live updates client: {} are already connected", self.max_subscribers);
+            ctx.close(Some((ws::CloseCode::Again, "Too many live updates clients, try again later").into()));
+            ctx.stop();
+            return;
+        }
+
+        if let Some(updates) = self.updates.take() {ctx.add_stream(updates);}
+        ctx.run_interval(STOP_CHECK_INTERVAL, |socket, ctx| {
+            if socket.stop.load(Ordering::SeqCst)
+            {
+                ctx.close(Some(ws::CloseCode::Away.into()));
+                ctx.stop();
+            }
+        });
+        ctx.run_interval(PING_INTERVAL, |socket, ctx| {
+            if socket.last_heard.elapsed() > CLIENT_TIMEOUT
+            {
+                debug!("Closing a live updates socket that stopped answering");
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context)
+    {
+        self.subscribers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for PriceSocket
+{
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context)
+    {
+        self.last_heard = Instant::now();
+        match msg
+        {
+            Ok(ws::Message::Ping(message)) => {ctx.pong(&message);},
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            },
+            Ok(_) => {},
+            Err(_) => {ctx.stop();}
+        }
+    }
+}
+
+impl StreamHandler<Result<PriceUpdate, RecvError>> for PriceSocket
+{
+    fn handle(&mut self, update: Result<PriceUpdate, RecvError>, ctx: &mut Self::Context)
+    {
+        match update
+        {
+            Ok(update) => {ctx.text(update_message(&update, self.price_scale).to_string());},
+            //a slow client misses the oldest updates rather than holding up the others
+            Err(RecvError::Lagged(missed)) => {debug!("Live updates socket fell behind and missed {} prices", missed);},
+            Err(RecvError::Closed) => {ctx.stop();}
+        }
+    }
+}
+
+/**
+Formats a stored price as the message sent to live updates clients.
+*/
+fn update_message(update: &PriceUpdate, price_scale: u64) -> serde_json::Value
+{
+    json!({"pair": update.pair, "when": update.when, "price_cents": cents_json(update.price as i64, price_scale)})
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use tokio::sync::broadcast;
+
+    // update_message
+    #[test]
+    fn update_messages()
+    {
+        let update = PriceUpdate{when: 1586304000, price: 682345, pair: String::from("btcusd")};
+        assert_eq!(update_message(&update, 100), json!({"pair": "btcusd", "when": 1586304000, "price_cents": 682345}));
+        assert_eq!(update_message(&update, 1000), json!({"pair": "btcusd", "when": 1586304000, "price_cents": 68234.5}));
+    }
+
+    /**
+    Starts a server with the live updates endpoint, sending what's sent on `sender`, counting its clients in `subscribers`
+    and shutting down when `stop` is set.
+    */
+    fn live_server(sender: &broadcast::Sender<PriceUpdate>, subscribers: &'static AtomicUsize, max_subscribers: usize, stop: &'static AtomicBool) -> actix_web::test::TestServer
+    {
+        use actix_web::{test, App};
+        let subscribe = sender.clone();
+        test::start(move || {
+            let subscribe = subscribe.clone();
+            App::new().route("/ws/prices", web::get().to(move |req: HttpRequest, stream: web::Payload| {
+                let socket = PriceSocket{updates: Some(subscribe.subscribe()), price_scale: 100, subscribers, max_subscribers, stop, last_heard: Instant::now()};
+                async move { ws::start(socket, &req, stream) }
+            }))
+        })
+    }
+
+    // prices
+    #[actix_rt::test]
+    async fn updates_pushed()
+    {
+        use actix_http::ws::Frame;
+        use futures::StreamExt;
+        static SUBSCRIBERS: AtomicUsize = AtomicUsize::new(0);
+        static STOP: AtomicBool = AtomicBool::new(false);
+
+        let (sender, _) = broadcast::channel(4);
+        let mut srv = live_server(&sender, &SUBSCRIBERS, 10, &STOP);
+
+        //the socket is listening once the handshake is done
+        let mut conn = srv.ws_at("/ws/prices").await.unwrap();
+        sender.send(PriceUpdate{when: 1586304000, price: 682345, pair: String::from("btcusd")}).unwrap();
+        sender.send(PriceUpdate{when: 1586304000, price: 18011, pair: String::from("ethusd")}).unwrap();
+        for expected in &[json!({"pair": "btcusd", "when": 1586304000, "price_cents": 682345}), json!({"pair": "ethusd", "when": 1586304000, "price_cents": 18011})]
+        {
+            match conn.next().await
+            {
+                Some(Ok(Frame::Text(body))) => {assert_eq!(&serde_json::from_slice::<serde_json::Value>(&body).unwrap(), expected);},
+                other => {panic!("expected a text message, got {:?}", other);}
+            }
+        }
+    }
+
+    // prices
+    #[actix_rt::test]
+    async fn subscribers_capped()
+    {
+        use actix_http::ws::Frame;
+        use actix_rt::time::delay_for;
+        use futures::StreamExt;
+        static SUBSCRIBERS: AtomicUsize = AtomicUsize::new(0);
+        static STOP: AtomicBool = AtomicBool::new(false);
+
+        let (sender, _) = broadcast::channel(4);
+        let mut srv = live_server(&sender, &SUBSCRIBERS, 2, &STOP);
+        let mut first = srv.ws_at("/ws/prices").await.unwrap();
+        let mut second = srv.ws_at("/ws/prices").await.unwrap();
+
+        //the third is over the limit
+        let mut third = srv.ws_at("/ws/prices").await.unwrap();
+        match third.next().await
+        {
+            Some(Ok(Frame::Close(Some(reason)))) => {assert_eq!(reason.code, ws::CloseCode::Again);},
+            other => {panic!("expected a close frame, got {:?}", other);}
+        }
+
+        //the ones let in still get updates
+        sender.send(PriceUpdate{when: 1586304000, price: 682345, pair: String::from("btcusd")}).unwrap();
+        for conn in [&mut first, &mut second].iter_mut()
+        {
+            assert!(matches!(conn.next().await, Some(Ok(Frame::Text(_)))));
+        }
+
+        //shutting down closes them, which frees their places
+        STOP.store(true, Ordering::SeqCst);
+        for conn in [&mut first, &mut second].iter_mut()
+        {
+            match conn.next().await
+            {
+                Some(Ok(Frame::Close(Some(reason)))) => {assert_eq!(reason.code, ws::CloseCode::Away);},
+                other => {panic!("expected a close frame, got {:?}", other);}
+            }
+        }
+        for _ in 0..50
+        {
+            if SUBSCRIBERS.load(Ordering::SeqCst) == 0 {break;}
+            delay_for(Duration::from_millis(20)).await;
+        }
+        assert_eq!(SUBSCRIBERS.load(Ordering::SeqCst), 0);
+    }
+}
@@ -1,108 +1,1952 @@
-use actix_web::{web, HttpResponse, http::header, http::StatusCode};
+use actix_web::{dev::ResourceDef, dev::ServiceResponse, error::InternalError, error::PathError, web, Error, HttpRequest, HttpResponse, Route, http::header, http::HeaderMap, http::HeaderValue, http::Method, http::StatusCode};
 use actix_http::ResponseBuilder;
-/*use log::{error, warn, info, debug, trace, log, Level};*/
+use chrono::{SecondsFormat, TimeZone, Utc};
+use futures::{stream, Future};
+use log::{error, warn, /*info, debug, trace, log, Level*/};
+use serde::Serialize;
+use serde_json::json;
 use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::{Semaphore, SemaphorePermit};
 
+use crate::analysis;
+use crate::settings::{AssetVersion, EmptyRange, Http, DEFAULT_PAIR, SETTINGS};
 use crate::sql;
+use crate::trading_hours::{self, TradingWindow, TRADING_WINDOWS};
+use crate::updater::{RecentPrices, FETCH_INTERVAL_SECS, RECENT_PRICES, UPDATER_STATUS};
+
+pub mod admin;
+pub mod compress;
+pub mod live;
+pub mod sparkline;
+
+/**
+Responds to requests for the main page at the domain root.
+
+# Returns
+HttpResponse containing the main page, which is the same every time -- everything dynamic is in the frontend code.
+The exception is a new install with no prices stored yet, which gets a notice to check back instead of an empty chart, if `empty_notice` is on.
+With `embed_initial_data` on, the page also carries the prices for the chart's starting range.
+*/
+pub async fn index() -> HttpResponse
+{
+    let has_data = !SETTINGS.http.empty_notice || has_price_data().await;
+    let initial = if has_data && SETTINGS.http.embed_initial_data {initial_prices().await} else {None};
+    let html = index_html(has_data, initial.as_ref(), &ASSET_VERSIONS);
+
+    ResponseBuilder::new(StatusCode::OK)
+        .set_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(html)
+}
+
+/// Set once any price has been seen stored, after which it's never checked again
+static HAS_PRICE_DATA: AtomicBool = AtomicBool::new(false);
+
+/**
+Whether any prices are stored, for the main page.
+If that can't be found out right away, because of a database error or too many requests querying it, this assumes there are.
+*/
+async fn has_price_data() -> bool
+{
+    if HAS_PRICE_DATA.load(Ordering::Relaxed) {return true;}
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(_) => {return true;}};
+    let query = "SELECT CAST(EXISTS(SELECT 1 FROM `price_history` WHERE `pair` = ?) AS UNSIGNED)";
+    let has_data = match sql::fetch_rows::<(u64,)>(query, vec![DEFAULT_PAIR.into()], "checking for any price data").await
+    {
+        Ok(rows) => rows.first() != Some(&(0,)),
+        Err(_) => true
+    };
+    if has_data {HAS_PRICE_DATA.store(true, Ordering::Relaxed);}
+    has_data
+}
+
+/// Start of the range the main page's chart shows first, matching `earliest_data_ts` in main.js
+const CHART_START: u64 = 1325347200;
+
+/**
+The prices for the range the main page's chart shows first, as the prices endpoint gives them by default, to embed in the page.
+If they can't be gotten right away, because of a database error, too many requests querying it, or too few prices stored yet,
+the page goes without them and the chart fetches them itself.
+*/
+async fn initial_prices() -> Option<serde_json::Value>
+{
+    let end = chrono::offset::Utc::now().timestamp() as u64;
+    let begin = cmp::min(CHART_START, end);
+    let _permit = db_permit(&DB_PERMITS).ok()?;
+    if warming_up(SETTINGS.http.min_points, &WARMED_UP, stored_points).await.is_some() {return None;}
+    let prices = sql::price_range(begin, end, segment_size(begin, end, DEFAULT_SEGMENTS), false).await.ok()?;
+    let rows = prices.into_iter().map(|(when, price, synthetic)| (when, Some(cents_json(price as i64, SETTINGS.updater.price_scale)), synthetic)).collect();
+    Some(serde_json::Value::Array(price_points_json(rows, false, false, None)))
+}
+
+/**
+Builds the main page.
+
+# Parameters
+- `has_data`: Whether to show the chart. Without data, a notice to check back is shown instead.
+- `initial`: Prices for the chart's starting range to embed as `initial_prices`, from `initial_prices`. Without them the chart fetches its own.
+- `versions`: Cache-busting versions of the local static files, from `asset_versions`
+*/
+fn index_html(has_data: bool, initial: Option<&serde_json::Value>, versions: &HashMap<String, String>) -> String
+{
+    if !has_data
+    {
+        let body = "<div id='collecting'><h1>Collecting data</h1><p>No prices have been stored yet. Check back soon.</p></div>";
+        return html_construct("Home - Bitcoin Trend", "", body, versions);
+    }
+    let body = format!("<div id='price_chart_container'><canvas id='price_chart'></canvas></div><br/><div id='slider'></div><br/><span id='begin'></span> - <span id='end'></span><img src='{}' id='spinner'/>",
+        asset_url("static/loading.gif", versions));
+    let mut head = String::new();
+    if let Some(points) = initial
+    {
+        //keep anything in the data from closing the script early
+        head.push_str(&format!("<script>var initial_prices = {};</script>", points.to_string().replace("</", "<\\/")));
+    }
+    head.push_str("<script>$( function() {chart_init();});</script>");
+
+    html_construct("Home - Bitcoin Trend", &head, &body, versions)
+}
+
+lazy_static!
+{
+    /// Permits to query the database, one per request doing so
+    pub(crate) static ref DB_PERMITS: Semaphore = Semaphore::new(SETTINGS.http.max_concurrent_queries);
+}
+
+/// Seconds clients are told to wait before retrying when too many requests are querying the database
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// When set, the data endpoints under /api answer 503 instead of querying the database. Toggled by the admin endpoint "maintenance".
+pub static MAINTENANCE: AtomicBool = AtomicBool::new(false);
+
+/// Seconds clients are told to wait before retrying while in maintenance mode
+const MAINTENANCE_RETRY_AFTER_SECS: u64 = 60;
+
+/// How many segments ranges are resampled over unless asked otherwise
+const DEFAULT_SEGMENTS: u64 = 100;
+
+/// Most segments a range in the "prices/batch" endpoint may ask for
+const MAX_SEGMENTS: u64 = 1000;
+
+/// Most ranges the "prices/batch" endpoint accepts in one request
+const MAX_BATCH_RANGES: usize = 20;
+
+/**
+Optional query string parameters accepted by the api endpoint "prices"
+*/
+#[derive(Deserialize)]
+pub struct PriceOptions
+{
+    /// When nonzero, each returned point gets a third element: true if it was made up only of virtual boundary rows rather than stored data
+    #[serde(default)]
+    pub mark_synthetic: u8,
+    /// Set to "ndjson" to stream the points as newline-delimited JSON, one point per line, instead of a single array
+    pub stream: Option<String>,
+    /// When nonzero, only stored points strictly within the range are used, instead of snapping each end of the range out to the closest point
+    #[serde(default)]
+    pub strict_bounds: u8,
+    /// Set to "long" or "short" to get each point as an object with named fields instead of an array, see `field_names`
+    pub fields: Option<String>,
+    /// Set to "null" to get a point with a null price for each segment with no data between the first and last points, instead of leaving a gap
+    pub fill: Option<String>,
+    /// When given, prices are rescaled so the first point equals this value (typically 100) and the rest are proportional to it
+    pub index: Option<f64>,
+    /// When nonzero, runs of consecutive points with the same price are collapsed to the first and last point of each run
+    #[serde(default)]
+    pub dedup: u8,
+    /// Set to "lttb" to pick the stored points that best keep the shape of the range with Largest-Triangle-Three-Buckets, instead of averaging segments.
+    /// The points keep their own times, so they aren't evenly spaced.
+    pub downsample: Option<String>,
+    /// Set to "iso" to get each point's time as an RFC 3339 string in UTC, like "2020-04-08T12:00:00Z", instead of Unix seconds
+    pub time_format: Option<String>,
+    /// How many segments to resample the range over, or with `downsample` how many points to keep. 100 if not given, and at most `max_points`.
+    pub points: Option<u64>
+}
+
+/**
+Responds to requests for the api endpoint "prices"
+
+# Parameters
+- `req`: The request, whose Accept header chooses the encoding
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse containing (if successful) JSON with the requested data, or the same data as MessagePack or CBOR
+if the Accept header prefers "application/msgpack" or "application/cbor".
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn api(req: HttpRequest, range: web::Path<(u64, u64)>, options: web::Query<PriceOptions>) -> HttpResponse
+{
+    match check_range(&range)
+    {
+        Ok((begin, end)) => prices_response(DEFAULT_PAIR, begin, end, &options, accepted_format(req.headers())).await,
+        Err(resp) => resp
+    }
+}
+
+/// How many stored prices the api endpoint "prices/raw" gives per page unless asked otherwise
+const DEFAULT_RAW_LIMIT: u64 = 1000;
+
+/// Most stored prices the api endpoint "prices/raw" gives per page
+const MAX_RAW_LIMIT: u64 = 10000;
+
+/**
+Optional query string parameters accepted by the api endpoint "prices/raw"
+*/
+#[derive(Deserialize)]
+pub struct RawOptions
+{
+    /// How many prices to give, 1000 if not given and at most 10000
+    #[serde(default = "default_raw_limit")]
+    pub limit: u64,
+    /// How many prices of the range to skip, for the pages after the first
+    #[serde(default)]
+    pub offset: u64
+}
+
+fn default_raw_limit() -> u64 {DEFAULT_RAW_LIMIT}
+
+/**
+Responds to requests for the api endpoint "prices/raw", which gives the stored prices of a range as they are, a page at a time.
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse containing (if successful) JSON with the page's `[when, price_cents]` points in `data`, in time order,
+and `next_offset`: the `offset` to ask for the next page with, or null on the last page.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn raw(range: web::Path<(u64, u64)>, options: web::Query<RawOptions>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+    if options.limit == 0 || options.limit > MAX_RAW_LIMIT
+    {
+        return json_response(StatusCode::BAD_REQUEST, format!("limit must be between 1 and {}", MAX_RAW_LIMIT));
+    }
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    //one more than the page, to tell whether there's another after it
+    match sql::raw_prices(begin, end, options.limit + 1, options.offset).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => json_response(StatusCode::OK, raw_page(rows, options.limit, options.offset, SETTINGS.updater.price_scale))
+    }
+}
+
+/**
+Builds a page of the api endpoint "prices/raw".
+
+# Parameters
+- `rows`: The stored (when, price) rows from `offset` on, up to one more than `limit`
+- `limit`: How many rows the page has at most
+- `offset`: How many rows of the range came before the page
+- `price_scale`: Units per dollar the prices are stored in
+*/
+fn raw_page(mut rows: Vec<(u64, u64)>, limit: u64, offset: u64, price_scale: u64) -> serde_json::Value
+{
+    let more = rows.len() as u64 > limit;
+    rows.truncate(limit as usize);
+    let next_offset = if more {Some(offset + limit)} else {None};
+    let data: Vec<serde_json::Value> = rows.into_iter().map(|(when, price)| json!([when, cents_json(price as i64, price_scale)])).collect();
+    json!({"data": data, "next_offset": next_offset})
+}
+
+/**
+Optional query string parameters accepted by the api endpoint "prices" as CSV
+*/
+#[derive(Deserialize)]
+pub struct CsvOptions
+{
+    /// How many segments to resample the range over, 100 if not given, and at most `max_points`
+    pub points: Option<u64>
+}
+
+/**
+Responds to requests for the api endpoint "prices" as CSV, like "/api/prices/{begin}/{end}.csv", for spreadsheets and data frames.
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse streaming (if successful) CSV with a `when,price_cents` header row, then a row per point like those of the "prices" endpoint.
+The virtual points at the beginning and end of time are left out, since a CSV has no column to mark them.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will be JSON containing only a string describing the error.
+*/
+pub async fn prices_csv(range: web::Path<(u64, u64)>, options: web::Query<CsvOptions>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+    let points = match point_count(options.points, SETTINGS.http.max_points) {Ok(n) => n, Err(resp) => {return resp;}};
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    let rows = match sql::price_range(begin, end, segment_size(begin, end, points), false).await
+    {
+        Err(e) => {return json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e));},
+        Ok(r) => r
+    };
+    let now = chrono::offset::Utc::now().timestamp();
+    let points = stored_series(rows).into_iter().map(|(when, price)| (when, cents_json(price as i64, SETTINGS.updater.price_scale)));
+    let lines = csv_lines(points).into_iter().map(|line| Ok::<_, Error>(web::Bytes::from(line)));
+    ResponseBuilder::new(StatusCode::OK)
+        .set_header(header::CONTENT_TYPE, "text/csv; charset=utf-8")
+        .set_header(header::CACHE_CONTROL, cache_control(end, now, &SETTINGS.http))
+        .streaming(stream::iter(lines))
+}
+
+/**
+Responds to requests for the api endpoint "prices" for a currency pair, like "/api/prices/ethusd/{begin}/{end}"
+
+# Parameters
+- `req`, `options`: Same as for `api`
+- `path`: actix-generated tuple containing the captured parameters "pair", "begin" and "end"
+
+# Returns
+Same as for `api`, for one of the pairs listed in `[pairs]`, or a 404 for any other pair.
+*/
+pub async fn pair_api(req: HttpRequest, path: web::Path<(String, u64, u64)>, options: web::Query<PriceOptions>) -> HttpResponse
+{
+    let (pair, begin, end) = path.into_inner();
+    if !SETTINGS.pairs.symbols.contains(&pair)
+    {
+        return json_response(StatusCode::NOT_FOUND, format!("Unknown pair: {}", pair));
+    }
+    match ordered_range(begin, end)
+    {
+        Ok((begin, end)) => prices_response(&pair, begin, end, &options, accepted_format(req.headers())).await,
+        Err(resp) => resp
+    }
+}
+
+/**
+The prices of a pair over a range, in the shape asked for by `options` and encoded in `format` unless streamed, for `api` and `pair_api`.
+*/
+async fn prices_response(pair: &str, begin: u64, end: u64, options: &PriceOptions, format: BodyFormat) -> HttpResponse
+{
+    let ndjson = match options.stream.as_deref()
+    {
+        None => false,
+        Some("ndjson") => true,
+        Some(_) => {return json_response(StatusCode::BAD_REQUEST, "stream must be \"ndjson\" if given");}
+    };
+    let names = match field_names(options.fields.as_deref())
+    {
+        Some(n) => n,
+        None => {return json_response(StatusCode::BAD_REQUEST, "fields must be \"long\" or \"short\" if given");}
+    };
+    let fill_null = match options.fill.as_deref()
+    {
+        None => false,
+        Some("null") => true,
+        Some(_) => {return json_response(StatusCode::BAD_REQUEST, "fill must be \"null\" if given");}
+    };
+    if let Some(base) = options.index
+    {
+        if !base.is_finite() || base <= 0.0 {return json_response(StatusCode::BAD_REQUEST, "index must be a positive number if given");}
+    }
+    let lttb = match options.downsample.as_deref()
+    {
+        None => false,
+        Some("lttb") => true,
+        Some(_) => {return json_response(StatusCode::BAD_REQUEST, "downsample must be \"lttb\" if given");}
+    };
+    let iso_time = match options.time_format.as_deref()
+    {
+        None => false,
+        Some("iso") => true,
+        Some(_) => {return json_response(StatusCode::BAD_REQUEST, "time_format must be \"iso\" if given");}
+    };
+    let points = match point_count(options.points, SETTINGS.http.max_points)
+    {
+        Ok(n) => n,
+        Err(resp) => {return resp;}
+    };
+    //downsampled points aren't in segments, so there are no empty segments to fill
+    if lttb && fill_null {return json_response(StatusCode::BAD_REQUEST, "fill can't be used with downsample");}
+
+    //LTTB picks from every stored point, which a segment of one second gives us
+    let segment_size = if lttb {1} else {segment_size(begin, end, points)};
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    if let Some(resp) = warming_up(SETTINGS.http.min_points, &WARMED_UP, stored_points).await {return resp;}
+    let prices = match sql::pair_price_range(pair, begin, end, segment_size, options.strict_bounds != 0).await
+    {
+        Err(e) => {return json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e));},
+        Ok(r) if lttb => lttb_rows(r, points as usize),
+        Ok(r) => r
+    };
+    if let Some(status) = empty_range_status(&prices, SETTINGS.http.empty_range)
+    {
+        return ResponseBuilder::new(status).finish();
+    }
+
+    let now = chrono::offset::Utc::now().timestamp();
+    let mark_synthetic = options.mark_synthetic != 0;
+    let fill_range = if fill_null {Some((begin, end))} else {None};
+    let points = match options.index
+    {
+        None => {
+            let rows = prices.into_iter().map(|(when, price, synthetic)| (when, cents_json(price as i64, SETTINGS.updater.price_scale), synthetic)).collect();
+            finish_points(rows, segment_size, fill_range, options.dedup != 0, mark_synthetic, iso_time, names)
+        },
+        Some(base) => {
+            let stored: Vec<u64> = prices.iter().map(|&(_, price, _)| price).collect();
+            let indexed = match analysis::index_series(&stored, base)
+            {
+                None => {return json_response(StatusCode::BAD_REQUEST, "Can't index a range whose first price is zero");},
+                Some(i) => i
+            };
+            let rows = prices.into_iter().zip(indexed).map(|((when, _, synthetic), value)| (when, value, synthetic)).collect();
+            finish_points(rows, segment_size, fill_range, options.dedup != 0, mark_synthetic, iso_time, names)
+        }
+    };
+    if ndjson
+    {
+        let lines = ndjson_lines(points).into_iter().map(|line| Ok::<_, Error>(web::Bytes::from(line)));
+        return ResponseBuilder::new(StatusCode::OK)
+            .set_header(header::CONTENT_TYPE, "application/x-ndjson")
+            .set_header(header::CACHE_CONTROL, cache_control(end, now, &SETTINGS.http))
+            .streaming(stream::iter(lines));
+    }
+
+    let body = match encode_body(&points, format)
+    {
+        Ok(b) => b,
+        Err(e) => {return json_response(StatusCode::INTERNAL_SERVER_ERROR, e);}
+    };
+    ResponseBuilder::new(StatusCode::OK)
+        .set_header(header::CONTENT_TYPE, format.content_type())
+        .set_header(header::CACHE_CONTROL, cache_control(end, now, &SETTINGS.http))
+        .set_header(header::VARY, "accept")
+        .body(body)
+}
+
+/**
+Encodings the prices endpoint can give its points in, chosen by the request's Accept header.
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum BodyFormat
+{
+    Json,
+    MessagePack,
+    Cbor
+}
+
+impl BodyFormat
+{
+    /// Content-Type of a body in this encoding
+    fn content_type(self) -> &'static str
+    {
+        match self
+        {
+            BodyFormat::Json => "application/json; charset=utf-8",
+            BodyFormat::MessagePack => "application/msgpack",
+            BodyFormat::Cbor => "application/cbor"
+        }
+    }
+}
+
+/**
+Chooses how to encode a response from the Accept header of its request.
+The media types with the highest quality value win, and one named outright beats a wildcard with the same quality.
+
+# Returns
+MessagePack for "application/msgpack" or "application/x-msgpack", CBOR for "application/cbor",
+and JSON for anything else, including no Accept header at all.
+*/
+fn accepted_format(headers: &HeaderMap) -> BodyFormat
+{
+    let accept = match headers.get(header::ACCEPT).and_then(|v| v.to_str().ok())
+    {
+        Some(a) => a,
+        None => {return BodyFormat::Json;}
+    };
+    let mut best = (BodyFormat::Json, 0.0, false);
+    for range in accept.split(',')
+    {
+        let mut parts = range.split(';').map(str::trim);
+        let (format, named) = match parts.next().unwrap_or("").to_ascii_lowercase().as_str()
+        {
+            "application/json" => (BodyFormat::Json, true),
+            "application/msgpack" | "application/x-msgpack" => (BodyFormat::MessagePack, true),
+            "application/cbor" => (BodyFormat::Cbor, true),
+            "application/*" | "*/*" => (BodyFormat::Json, false),
+            _ => {continue;}
+        };
+        let quality = parts.filter_map(|p| p.strip_prefix("q=")).next().and_then(|q| q.parse::<f32>().ok()).unwrap_or(1.0);
+        if quality > best.1 || (quality > 0.0 && quality == best.1 && named && !best.2) {best = (format, quality, named);}
+    }
+    best.0
+}
+
+/**
+Encodes the body of a response.
+
+# Errors
+String describing why the body couldn't be encoded.
+*/
+fn encode_body<T: Serialize>(body: &T, format: BodyFormat) -> Result<Vec<u8>, String>
+{
+    match format
+    {
+        BodyFormat::Json => serde_json::to_vec(body).map_err(|e| format!("Couldn't encode JSON: {}", e)),
+        BodyFormat::MessagePack => rmp_serde::to_vec(body).map_err(|e| format!("Couldn't encode MessagePack: {}", e)),
+        BodyFormat::Cbor => serde_cbor::to_vec(body).map_err(|e| format!("Couldn't encode CBOR: {}", e))
+    }
+}
+
+/**
+Checks the number of points asked for with the "points" parameter of the prices endpoint.
+
+# Parameters
+- `points`: Value of the "points" query string parameter, if given
+- `max`: Most points that may be asked for, as set by `max_points`
+
+# Returns
+How many points to give, 100 if not asked, or else a 400 Bad Request response to send.
+*/
+fn point_count(points: Option<u64>, max: u64) -> Result<u64, HttpResponse>
+{
+    match points
+    {
+        None => Ok(cmp::min(DEFAULT_SEGMENTS, max)),
+        Some(n) if (1..=max).contains(&n) => Ok(n),
+        Some(_) => Err(json_response(StatusCode::BAD_REQUEST, format!("points must be between 1 and {}", max)))
+    }
+}
+
+/// Set once `min_points` prices have been seen stored, after which they're never counted again
+static WARMED_UP: AtomicBool = AtomicBool::new(false);
+
+/**
+Makes sure enough prices are stored for the prices endpoint to serve a chart that isn't misleading, as set by `min_points`.
+
+# Parameters
+- `need`: Fewest prices that must be stored. 0 always serves.
+- `warmed_up`: Set once there have been enough, so they're only counted until then
+- `count`: Counts the stored prices, like `stored_points`. If it fails, the data is served anyway.
+
+# Returns
+None if the request may go on, or else the response to send instead: 503 Service Unavailable with JSON
+containing `status` "warming_up", the number of prices we `have` and the number we `need`.
+*/
+async fn warming_up<F, Fut>(need: u64, warmed_up: &AtomicBool, count: F) -> Option<HttpResponse>
+    where F: FnOnce() -> Fut, Fut: Future<Output = Result<u64,String>>
+{
+    if need == 0 || warmed_up.load(Ordering::Relaxed) {return None;}
+    let have = count().await.ok()?;
+    if have >= need
+    {
+        warmed_up.store(true, Ordering::Relaxed);
+        return None;
+    }
+    Some(json_response(StatusCode::SERVICE_UNAVAILABLE, json!({"status": "warming_up", "have": have, "need": need})))
+}
+
+/**
+Counts the prices stored, for `warming_up`.
+*/
+async fn stored_points() -> Result<u64,String>
+{
+    let rows = sql::fetch_rows::<(u64,)>("SELECT CAST(COUNT(*) AS UNSIGNED) FROM `price_history` WHERE `pair` = ?", vec![DEFAULT_PAIR.into()], "counting stored prices").await?;
+    Ok(rows.first().map_or(0, |&(count,)| count))
+}
+
+/**
+One of the ranges requested from the api endpoint "prices/batch"
+*/
+#[derive(Deserialize)]
+pub struct BatchRange
+{
+    /// Unix timestamp where the range begins
+    pub begin: u64,
+    /// Unix timestamp where the range ends
+    pub end: u64,
+    /// How many segments to resample the range over, 100 if not given
+    #[serde(default = "default_segments")]
+    pub segments: u64
+}
+
+fn default_segments() -> u64 {DEFAULT_SEGMENTS}
+
+/**
+Responds to requests for the api endpoint "prices/batch", which fetches several ranges at once.
+
+# Parameters
+- `ranges`: actix-generated vector of the ranges in the JSON request body, an array of `{begin, end, segments}` objects
+
+# Returns
+HttpResponse containing JSON with one object per requested range, in the same order:
+`{"data": [...]}` with points like those of the "prices" endpoint, or `{"error": "..."}` if that range failed.
+A bad range doesn't keep the rest from being returned.
+
+# Errors
+The whole request fails with 400 Bad Request if it has too many ranges; the body will be JSON containing only a string describing the error.
+*/
+pub async fn batch(ranges: web::Json<Vec<BatchRange>>) -> HttpResponse
+{
+    if ranges.len() > MAX_BATCH_RANGES
+    {
+        return json_response(StatusCode::BAD_REQUEST, format!("At most {} ranges can be requested at once", MAX_BATCH_RANGES));
+    }
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    let results = batch_results(&ranges, SETTINGS.updater.price_scale,
+        |begin, end, segment_size| sql::price_range(begin, end, segment_size, false)).await;
+    json_response(StatusCode::OK, results)
+}
+
+/**
+Fetches each range of a batch in turn, turning each into either its points or an error.
+
+# Parameters
+- `ranges`: The requested ranges
+- `price_scale`: Units per dollar the prices are stored in
+- `fetch`: Gets the rows of a range given (begin, end, segment_size), like `sql::price_range`
+
+# Returns
+One `{"data": [...]}` or `{"error": "..."}` object per range.
+*/
+async fn batch_results<F, Fut>(ranges: &[BatchRange], price_scale: u64, fetch: F) -> Vec<serde_json::Value>
+    where F: Fn(u64, u64, u64) -> Fut, Fut: Future<Output = Result<Vec<(u64,u64,bool)>,String>>
+{
+    let mut results = Vec::with_capacity(ranges.len());
+    for range in ranges
+    {
+        if range.end < range.begin
+        {
+            results.push(json!({"error": "begin must be <= end"}));
+            continue;
+        }
+        if range.segments == 0 || range.segments > MAX_SEGMENTS
+        {
+            results.push(json!({"error": format!("segments must be between 1 and {}", MAX_SEGMENTS)}));
+            continue;
+        }
+
+        let segment_size = segment_size(range.begin, range.end, range.segments);
+        results.push(match fetch(range.begin, range.end, segment_size).await
+        {
+            Err(e) => json!({"error": format!("Database error: {}", e)}),
+            Ok(rows) => {
+                let rows = rows.into_iter().map(|(when, price, synthetic)| (when, cents_json(price as i64, price_scale), synthetic)).collect();
+                json!({"data": finish_points(rows, segment_size, None, false, false, false, None)})
+            }
+        });
+    }
+    results
+}
+
+/**
+Decides whether a range should get a special response because there is no stored data in it.
+
+# Parameters
+- `rows`: Tuples of (when, price, synthetic) as returned by the range query
+- `mode`: The configured `empty_range` behavior
+
+# Returns
+The status to respond with instead of the points, or None to respond with the points as usual.
+*/
+fn empty_range_status(rows: &[(u64,u64,bool)], mode: EmptyRange) -> Option<StatusCode>
+{
+    let has_data = rows.iter().any(|&(_, _, synthetic)| !synthetic);
+    match mode
+    {
+        EmptyRange::NoContent if !has_data => Some(StatusCode::NO_CONTENT),
+        _ => None
+    }
+}
+
+/**
+Decides how long clients may cache price data for a range.
+
+Data for a range that ended long ago won't change, so it can be cached for a long time,
+but a range touching the present gets new points whenever the updater runs.
+
+# Parameters
+- `end`: Unix timestamp where the requested range ends
+- `now`: Current unix timestamp
+- `http`: The HTTP settings holding the recent window and the max-age for each case
+
+# Returns
+Value for the Cache-Control header.
+*/
+fn cache_control(end: u64, now: i64, http: &Http) -> String
+{
+    let recent_start = now - http.recent_window_secs as i64;
+    let max_age = if end < recent_start.max(0) as u64 {http.historical_max_age_secs} else {http.recent_max_age_secs};
+    if max_age == 0 {String::from("no-cache")} else {format!("public, max-age={}", max_age)}
+}
+
+/**
+Responds to requests for the api endpoint "by_hour_of_day"
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+
+# Returns
+HttpResponse containing (if successful) JSON with one `[hour, avg_price_cents, count]` array for each hour of the day (UTC), 0 through 23.
+`avg_price_cents` is null for hours without any data in the range.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn by_hour_of_day(range: web::Path<(u64, u64)>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::price_by_hour_of_day(begin, end).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => json_response(StatusCode::OK, hour_of_day_buckets(rows, SETTINGS.updater.price_scale))
+    }
+}
+
+/**
+Optional query string parameters accepted by the api endpoint "rolling_return"
+*/
+#[derive(Deserialize)]
+pub struct RollingReturnOptions
+{
+    /// How many days back to compare each point to
+    #[serde(default = "default_window_days")]
+    pub window_days: u64
+}
+
+fn default_window_days() -> u64 {30}
+
+/**
+Responds to requests for the api endpoint "rolling_return"
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse containing (if successful) JSON with one `[when, percent]` array per point of the resampled range,
+where percent is the change in price since `window_days` earlier. It is null for points without enough history before them.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn rolling_return(range: web::Path<(u64, u64)>, options: web::Query<RollingReturnOptions>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+    let segment_size = segment_size(begin, end, DEFAULT_SEGMENTS);
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::price_range(begin, end, segment_size, false).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => {
+            let series = stored_series(rows);
+            json_response(StatusCode::OK, analysis::rolling_returns(&series, segment_size, options.window_days.saturating_mul(86400)))
+        }
+    }
+}
+
+/**
+Optional query string parameters accepted by the api endpoint "bollinger"
+*/
+#[derive(Deserialize)]
+pub struct BollingerOptions
+{
+    /// How many points of the resampled range each moving average covers
+    #[serde(default = "default_bollinger_period")]
+    pub period: usize,
+    /// How many standard deviations the bands are from the moving average
+    #[serde(default = "default_bollinger_k")]
+    pub k: f64
+}
+
+fn default_bollinger_period() -> usize {20}
+fn default_bollinger_k() -> f64 {2.0}
+
+/**
+Responds to requests for the api endpoint "bollinger"
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse containing (if successful) JSON with one `[when, average_cents, upper_cents, lower_cents]` array per point of the resampled range:
+the moving average over `period` points and the bands `k` standard deviations either side of it.
+All three are null for points without a full period of points before them.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn bollinger(range: web::Path<(u64, u64)>, options: web::Query<BollingerOptions>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+    if options.period == 0 {return json_response(StatusCode::BAD_REQUEST, "period must be at least 1");}
+    if !options.k.is_finite() || options.k < 0.0 {return json_response(StatusCode::BAD_REQUEST, "k must be a number of at least 0");}
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::price_range(begin, end, segment_size(begin, end, DEFAULT_SEGMENTS), false).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => {
+            let series = stored_series(rows);
+            let to_cents = |v: Option<f64>| v.map(|v| v * 100.0 / SETTINGS.updater.price_scale as f64);
+            let bands: Vec<serde_json::Value> = analysis::bollinger(&series, options.period, options.k).into_iter()
+                .map(|b| json!([b.when, to_cents(b.average), to_cents(b.upper), to_cents(b.lower)]))
+                .collect();
+            json_response(StatusCode::OK, bands)
+        }
+    }
+}
+
+/**
+Optional query string parameters accepted by the api endpoint "candles"
+*/
+#[derive(Deserialize)]
+pub struct CandleOptions
+{
+    /// How many segments to combine the range into, 100 if not given
+    #[serde(default = "default_segments")]
+    pub segments: u64
+}
+
+/**
+Responds to requests for the api endpoint "candles"
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse containing (if successful) JSON with one `{time, open, high, low, close, volume}` object per segment that has data,
+the shape candlestick charts take. Prices are in cents and `volume` is in bitcoin, null when no stored candle in the segment had one.
+Prices stored without a candle count as a candle flat at that price.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn candles(range: web::Path<(u64, u64)>, options: web::Query<CandleOptions>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+    if options.segments == 0 || options.segments > MAX_SEGMENTS
+    {
+        return json_response(StatusCode::BAD_REQUEST, format!("segments must be between 1 and {}", MAX_SEGMENTS));
+    }
+
+    candles_response(DEFAULT_PAIR, begin, end, segment_size(begin, end, options.segments), 0).await
+}
+
+/// Most candles the api endpoint "candles" with an interval returns at once
+const MAX_CANDLES: u64 = 5000;
+
+/**
+Looks up the length of the candles asked for by the "interval" path parameter of the api endpoint "candles".
+
+# Returns
+The length in seconds and a time one of them starts at, with weeks starting on Monday, or None if the interval isn't one we know.
+*/
+fn candle_interval(interval: &str) -> Option<(u64, u64)>
+{
+    match interval
+    {
+        "1h" => Some((3600, 0)),
+        "4h" => Some((4 * 3600, 0)),
+        "1d" => Some((86400, 0)),
+        //1970-01-05, the first Monday after the epoch
+        "1w" => Some((7 * 86400, 4 * 86400)),
+        _ => None
+    }
+}
+
+/**
+Responds to requests for the api endpoint "candles" for a currency pair at a fixed interval, like "/api/candles/ethusd/{begin}/{end}/1d"
+
+# Parameters
+- `path`: actix-generated tuple containing the captured parameters "pair", "begin", "end" and "interval"
+
+# Returns
+HttpResponse containing (if successful) JSON with one candle per interval that has data, like `candles`.
+The interval is one of "1h", "4h", "1d" or "1w". Candles start on the hour, days at midnight UTC and weeks on Monday.
+
+# Errors
+404 Not Found for a pair that isn't listed in `[pairs]`, and 400 Bad Request for an unknown interval
+or a range that would have more than `MAX_CANDLES` candles. The body is JSON containing only a string describing the error.
+*/
+pub async fn pair_candles(path: web::Path<(String, u64, u64, String)>) -> HttpResponse
+{
+    let (pair, begin, end, interval) = path.into_inner();
+    if !SETTINGS.pairs.symbols.contains(&pair)
+    {
+        return json_response(StatusCode::NOT_FOUND, format!("Unknown pair: {}", pair));
+    }
+    let (begin, end) = match ordered_range(begin, end) {Ok(r) => r, Err(resp) => {return resp;}};
+    let (length, origin) = match candle_interval(&interval)
+    {
+        Some(i) => i,
+        None => {return json_response(StatusCode::BAD_REQUEST, "interval must be \"1h\", \"4h\", \"1d\" or \"1w\"");}
+    };
+    if (end - begin) / length >= MAX_CANDLES
+    {
+        return json_response(StatusCode::BAD_REQUEST, format!("A range can have at most {} candles; use a longer interval or a shorter range", MAX_CANDLES));
+    }
+    candles_response(&pair, begin, end, length, origin).await
+}
+
+/**
+The candles of a pair over a range, for `candles` and `pair_candles`.
+
+# Parameters
+- `segment_size`, `origin`: How to split the range into candles, as for `analysis::candles`
+*/
+async fn candles_response(pair: &str, begin: u64, end: u64, segment_size: u64, origin: u64) -> HttpResponse
+{
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::candle_rows(pair, begin, end).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => {
+            let price = |stored: u64| cents_json(stored as i64, SETTINGS.updater.price_scale);
+            let candles: Vec<serde_json::Value> = analysis::candles(&rows, segment_size, origin).into_iter()
+                .map(|(when, c)| json!({"time": when, "open": price(c.open), "high": price(c.high), "low": price(c.low), "close": price(c.close), "volume": c.volume}))
+                .collect();
+            json_response(StatusCode::OK, candles)
+        }
+    }
+}
+
+/**
+Optional query string parameters accepted by the api endpoint "momentum"
+*/
+#[derive(Deserialize)]
+pub struct MomentumOptions
+{
+    /// How many changes between points of the resampled range the averages cover
+    #[serde(default = "default_momentum_period")]
+    pub period: usize
+}
+
+fn default_momentum_period() -> usize {14}
+
+/**
+Responds to requests for the api endpoint "momentum"
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse containing (if successful) JSON with one `[when, score]` array per point of the resampled range, where the score is
+the Relative Strength Index over `period` changes, from 0 to 100. It is null for the first `period` points, which don't have enough history.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn momentum(range: web::Path<(u64, u64)>, options: web::Query<MomentumOptions>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+    if options.period == 0 {return json_response(StatusCode::BAD_REQUEST, "period must be at least 1");}
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::price_range(begin, end, segment_size(begin, end, DEFAULT_SEGMENTS), false).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => {
+            let series = stored_series(rows);
+            json_response(StatusCode::OK, analysis::rsi(&series, options.period))
+        }
+    }
+}
+
+/**
+Optional query string parameters accepted by the api endpoint "anomalies"
+*/
+#[derive(Deserialize)]
+pub struct AnomalyOptions
+{
+    /// Largest change from one stored price to the next, in percent, that isn't flagged
+    #[serde(default = "default_anomaly_threshold")]
+    pub threshold: f64
+}
+
+fn default_anomaly_threshold() -> f64 {20.0}
+
+/**
+Responds to requests for the api endpoint "anomalies", which flags stored prices that are likely bad data.
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse containing (if successful) JSON with one `[when, price_cents, delta_cents, percent]` array for each stored price in the range
+that changed by more than `threshold` percent from the stored price before it. Unlike the other endpoints, the range isn't resampled.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn anomalies(range: web::Path<(u64, u64)>, options: web::Query<AnomalyOptions>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+    if !options.threshold.is_finite() || options.threshold < 0.0 {return json_response(StatusCode::BAD_REQUEST, "threshold must be a number of at least 0");}
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    //segments of one second with strict bounds give back each stored point as it is
+    match sql::price_range(begin, end, 1, true).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => {
+            let series: Vec<(u64,u64)> = rows.into_iter().map(|(when, price, _)| (when, price)).collect();
+            let scale = SETTINGS.updater.price_scale;
+            let flagged: Vec<serde_json::Value> = analysis::anomalies(&series, options.threshold).into_iter()
+                .map(|a| json!([a.when, cents_json(a.price as i64, scale), cents_json(a.delta, scale), a.percent]))
+                .collect();
+            json_response(StatusCode::OK, flagged)
+        }
+    }
+}
+
+/**
+Optional query string parameters accepted by the api endpoint "calendar"
+*/
+#[derive(Deserialize)]
+pub struct CalendarOptions
+{
+    /// "day", "week" or "month"
+    #[serde(default = "default_calendar_unit")]
+    pub by: analysis::CalendarUnit,
+    /// IANA name of the time zone whose calendar is used, like "America/New_York". UTC if not given.
+    pub tz: Option<String>
+}
+
+fn default_calendar_unit() -> analysis::CalendarUnit {analysis::CalendarUnit::Day}
+
+/**
+Responds to requests for the api endpoint "calendar", the closing price of each day, week or month by a local calendar.
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse containing (if successful) JSON with one `[start, close_cents]` array per period of the range with stored prices:
+the timestamp of local midnight at the start of the period, and the last stored price in it. The range isn't resampled.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn calendar(range: web::Path<(u64, u64)>, options: web::Query<CalendarOptions>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+    let tz = match options.tz.as_deref().map(str::parse::<chrono_tz::Tz>)
+    {
+        None => chrono_tz::UTC,
+        Some(Ok(tz)) => tz,
+        Some(Err(_)) => {return json_response(StatusCode::BAD_REQUEST, "tz must be a time zone name like \"America/New_York\" if given");}
+    };
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    //segments of one second with strict bounds give back each stored point as it is
+    match sql::price_range(begin, end, 1, true).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => {
+            let series: Vec<(u64,u64)> = rows.into_iter().map(|(when, price, _)| (when, price)).collect();
+            let closes: Vec<serde_json::Value> = analysis::calendar_closes(&series, options.by, tz).into_iter()
+                .map(|(start, price)| json!([start, cents_json(price as i64, SETTINGS.updater.price_scale)]))
+                .collect();
+            json_response(StatusCode::OK, closes)
+        }
+    }
+}
+
+/// Most moves the "biggest_moves" endpoint will return
+const MAX_MOVES: u64 = 100;
+
+/**
+Optional query string parameters accepted by the api endpoint "biggest_moves"
+*/
+#[derive(Deserialize)]
+pub struct BiggestMovesOptions
+{
+    /// How many moves to return, clamped to between 1 and 100
+    #[serde(default = "default_moves")]
+    pub n: u64,
+    /// "up", "down" or "both"
+    #[serde(default = "default_move_direction")]
+    pub dir: analysis::MoveDirection
+}
+
+fn default_moves() -> u64 {10}
+fn default_move_direction() -> analysis::MoveDirection {analysis::MoveDirection::Both}
+
+/**
+Responds to requests for the api endpoint "biggest_moves"
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse containing (if successful) JSON with one `[when, delta_cents, percent]` array per segment of the resampled range
+that moved the most from the segment before, largest first. percent is null when the segment before had a price of zero.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn biggest_moves(range: web::Path<(u64, u64)>, options: web::Query<BiggestMovesOptions>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+    let n = options.n.clamp(1, MAX_MOVES) as usize;
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::price_range(begin, end, segment_size(begin, end, DEFAULT_SEGMENTS), false).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => {
+            let series = stored_series(rows);
+            let moves: Vec<serde_json::Value> = analysis::biggest_moves(&series, n, options.dir).into_iter()
+                .map(|(when, delta, percent)| json!([when, cents_json(delta, SETTINGS.updater.price_scale), percent]))
+                .collect();
+            json_response(StatusCode::OK, moves)
+        }
+    }
+}
+
+/**
+Responds to requests for the api endpoint "drawdown"
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+
+# Returns
+HttpResponse containing (if successful) JSON with the maximum drawdown of the resampled range: `max_percent`, the largest
+decline from a peak to a later trough, the `peak` and `trough` timestamps (null if prices never fell),
+and `current_percent`, the decline of the last segment from the highest one.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn drawdown(range: web::Path<(u64, u64)>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::price_range(begin, end, segment_size(begin, end, DEFAULT_SEGMENTS), false).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => {
+            let series = stored_series(rows);
+            json_response(StatusCode::OK, analysis::drawdown(&series))
+        }
+    }
+}
+
+/**
+Responds to requests for the api endpoint "returns_stats"
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+
+# Returns
+HttpResponse containing (if successful) JSON describing the percent changes from each segment of the resampled range to the next:
+their `count`, `mean`, `std_dev`, `skewness`, excess `kurtosis`, `min` and `max`.
+Statistics that aren't defined for so few segments are null.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn returns_stats(range: web::Path<(u64, u64)>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::price_range(begin, end, segment_size(begin, end, DEFAULT_SEGMENTS), false).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => {
+            let series = stored_series(rows);
+            json_response(StatusCode::OK, analysis::returns_stats(&series))
+        }
+    }
+}
+
+/**
+Responds to requests for the api endpoint "trend", a straight-line fit for drawing over a chart of the range.
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+
+# Returns
+HttpResponse containing (if successful) JSON describing the least-squares line through the resampled range, with time
+in seconds since `begin`: its `slope` in cents per second, `intercept` in cents, `r_squared`, and the cents it gives
+at the `start` and `end` of the range. `r_squared` is null when the prices don't vary. The whole body is null when the range has no prices.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn trend(range: web::Path<(u64, u64)>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::price_range(begin, end, segment_size(begin, end, DEFAULT_SEGMENTS), false).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => {
+            let series = stored_series(rows);
+            let to_cents = 100.0 / SETTINGS.updater.price_scale as f64;
+            let line = analysis::trend_line(&series, begin, end).map(|line| analysis::TrendLine{
+                slope: line.slope * to_cents,
+                intercept: line.intercept * to_cents,
+                start: line.start * to_cents,
+                end: line.end * to_cents,
+                ..line
+            });
+            json_response(StatusCode::OK, line)
+        }
+    }
+}
+
+/// Most days the "completeness" endpoint will report on, so a huge range can't make it build a huge response
+const MAX_COMPLETENESS_DAYS: u64 = 20000;
+
+/**
+Responds to requests for the api endpoint "completeness"
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+
+# Returns
+HttpResponse containing (if successful) JSON with one object per UTC day of the range, which can cover at most `MAX_COMPLETENESS_DAYS`: `day`, the timestamp of its midnight,
+`points` stored that day, the number `expected` from the updater's hourly fetches within the range and trading hours,
+and their `fraction`, which is null when none were expected.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn completeness(range: web::Path<(u64, u64)>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+    if end / 86400 - begin / 86400 >= MAX_COMPLETENESS_DAYS
+    {
+        return json_response(StatusCode::BAD_REQUEST, format!("range can cover at most {} days", MAX_COMPLETENESS_DAYS));
+    }
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::points_per_day(begin, end).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(counts) => json_response(StatusCode::OK, analysis::daily_completeness(begin, end, &counts, FETCH_INTERVAL_SECS, &TRADING_WINDOWS))
+    }
+}
+
+/**
+Responds to requests for the api endpoint "day_count", a quick measure of how much history is stored.
+
+# Returns
+HttpResponse containing (if successful) JSON with `days`: how many UTC days have any stored prices,
+and `first_day` and `last_day`: the timestamps of the midnights starting the first and last of them, which are null when nothing is stored.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when there's a database error.
+In this case the body will still be JSON, but it will only contain a string describing the error.
+*/
+pub async fn day_count() -> HttpResponse
+{
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::day_count().await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(None) => json_response(StatusCode::OK, json!({"days": 0, "first_day": null, "last_day": null})),
+        Ok(Some((days, first_day, last_day))) => json_response(StatusCode::OK, json!({"days": days, "first_day": first_day, "last_day": last_day}))
+    }
+}
+
+/// Most points the "recent" endpoint will return
+const MAX_RECENT: u64 = 1000;
+
+/**
+Responds to requests for the api endpoint "recent", the latest stored prices.
+These come from memory when they can, so they're cheap enough for things like sparklines to poll.
+
+# Parameters
+- `n`: actix-generated tuple containing the captured parameter "n", how many points to return, from 1 to 1000
+
+# Returns
+HttpResponse containing (if successful) JSON with one `[when, price_cents]` array per point, oldest first.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will still be JSON, but it will
+only contain a string describing the error.
+*/
+pub async fn recent(n: web::Path<(u64,)>) -> HttpResponse
+{
+    let n = n.0;
+    if n == 0 || n > MAX_RECENT
+    {
+        return json_response(StatusCode::BAD_REQUEST, format!("n must be between 1 and {}", MAX_RECENT));
+    }
+
+    let points = recent_points(&RECENT_PRICES, n as usize, |n| async move {
+        let _permit = db_permit(&DB_PERMITS)?;
+        let query = "SELECT CAST(`when` AS UNSIGNED), CAST(`price_cents` AS UNSIGNED) FROM `price_history` WHERE `pair` = ? ORDER BY `when` DESC LIMIT ?";
+        match sql::fetch_rows::<(u64,u64)>(query, vec![DEFAULT_PAIR.into(), (n as u64).into()], "getting recent prices").await
+        {
+            Err(e) => Err(json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e))),
+            Ok(mut rows) => {rows.reverse(); Ok(rows)}
+        }
+    }).await;
+
+    match points
+    {
+        Err(resp) => resp,
+        Ok(points) => {
+            let points: Vec<serde_json::Value> = points.into_iter()
+                .map(|(when, price)| json!([when, cents_json(price as i64, SETTINGS.updater.price_scale)]))
+                .collect();
+            json_response(StatusCode::OK, points)
+        }
+    }
+}
+
+/**
+Gets the latest prices from the in-memory buffer, only going to the database when the buffer can't answer.
+
+# Parameters
+- `buffer`: The recent prices kept by the updater
+- `n`: How many points to get
+- `fetch`: Gets the latest `n` rows in time order from the database
+
+# Returns
+The points in time order, or the error from `fetch`.
+*/
+async fn recent_points<F, Fut, E>(buffer: &Mutex<RecentPrices>, n: usize, fetch: F) -> Result<Vec<(u64,u64)>,E>
+    where F: FnOnce(usize) -> Fut, Fut: Future<Output = Result<Vec<(u64,u64)>,E>>
+{
+    let buffered = buffer.lock().ok().and_then(|recent| recent.latest(n));
+    match buffered
+    {
+        Some(points) => Ok(points),
+        None => fetch(n).await
+    }
+}
+
+/**
+Responds to requests for the api endpoint "freshness", how long ago the updater last stored a price. Meant for lightweight uptime probes.
+
+# Returns
+HttpResponse containing JSON with `last_success` (unix timestamp), `seconds_ago`, and `stale`: true once that's longer than `stale_after_secs`.
+Until the updater has stored a price since startup, `last_success` and `seconds_ago` are null and `stale` is true.
+
+# Errors
+The HttpResponse can also indicate failure if the updater's status can't be read, in which case the body will be JSON containing only a string describing the error.
+*/
+pub async fn freshness() -> HttpResponse
+{
+    let now = chrono::offset::Utc::now().timestamp() as u64;
+    match UPDATER_STATUS.lock()
+    {
+        Err(_) => json_response(StatusCode::INTERNAL_SERVER_ERROR, "Couldn't read the updater's status"),
+        Ok(status) => json_response(StatusCode::OK, status.freshness(now, SETTINGS.updater.stale_after_secs))
+    }
+}
+
+/**
+An endpoint under /api, as registered with the server and listed by the index at /api.
+*/
+pub struct ApiEndpoint
+{
+    pub method: Method,
+    /// Path pattern, with parameters in braces as actix takes them
+    pub path: &'static str,
+    /// One line about what it returns
+    pub description: &'static str,
+    /// Attaches the handler to a route for `method`
+    handler: fn(Route) -> Route
+}
+
+/// Every endpoint under /api. The routes are registered from this by `configure_api`, so the index can't miss one.
+pub static API_ENDPOINTS: &[ApiEndpoint] = &[
+    ApiEndpoint{method: Method::GET, path: "/api", description: "This list of endpoints", handler: |r| r.to(api_index)},
+    ApiEndpoint{method: Method::GET, path: "/api/prices/{begin}/{end:\\d+}.csv", description: "Prices over a range as CSV, with a header row", handler: |r| r.to(prices_csv)},
+    ApiEndpoint{method: Method::GET, path: "/api/prices/{begin}/{end}", description: "Prices over a range, resampled into segments", handler: |r| r.to(api)},
+    ApiEndpoint{method: Method::GET, path: "/api/prices/raw/{begin}/{end}", description: "Stored prices over a range as they are, a page at a time", handler: |r| r.to(raw)},
+    ApiEndpoint{method: Method::GET, path: "/api/prices/{pair}/{begin}/{end}", description: "Prices of one of the configured currency pairs over a range, like ethusd", handler: |r| r.to(pair_api)},
+    ApiEndpoint{method: Method::POST, path: "/api/prices/batch", description: "Several ranges of prices in one request, given as a JSON array", handler: |r| r.to(batch)},
+    ApiEndpoint{method: Method::GET, path: "/api/by_hour_of_day/{begin}/{end}", description: "Average price per hour of the day", handler: |r| r.to(by_hour_of_day)},
+    ApiEndpoint{method: Method::GET, path: "/api/rolling_return/{begin}/{end}", description: "Percent change over a trailing window", handler: |r| r.to(rolling_return)},
+    ApiEndpoint{method: Method::GET, path: "/api/sparkline/{begin}/{end:\\d+}.svg", description: "Small SVG line chart of a range", handler: |r| r.to(sparkline::sparkline)},
+    ApiEndpoint{method: Method::GET, path: "/api/biggest_moves/{begin}/{end}", description: "Segments that moved the most from the one before", handler: |r| r.to(biggest_moves)},
+    ApiEndpoint{method: Method::GET, path: "/api/drawdown/{begin}/{end}", description: "Largest peak-to-trough decline", handler: |r| r.to(drawdown)},
+    ApiEndpoint{method: Method::GET, path: "/api/returns_stats/{begin}/{end}", description: "Mean, spread, skewness and kurtosis of the returns from segment to segment", handler: |r| r.to(returns_stats)},
+    ApiEndpoint{method: Method::GET, path: "/api/trend/{begin}/{end}", description: "Least-squares trend line through the range", handler: |r| r.to(trend)},
+    ApiEndpoint{method: Method::GET, path: "/api/bollinger/{begin}/{end}", description: "Moving average with bands a number of standard deviations either side", handler: |r| r.to(bollinger)},
+    ApiEndpoint{method: Method::GET, path: "/api/candles/{begin}/{end}", description: "Open, high, low, close and volume per segment, for candlestick charts", handler: |r| r.to(candles)},
+    ApiEndpoint{method: Method::GET, path: "/api/candles/{pair}/{begin}/{end}/{interval}", description: "Candles of one of the configured currency pairs at an interval of 1h, 4h, 1d or 1w", handler: |r| r.to(pair_candles)},
+    ApiEndpoint{method: Method::GET, path: "/api/momentum/{begin}/{end}", description: "Relative Strength Index, a 0 to 100 momentum score", handler: |r| r.to(momentum)},
+    ApiEndpoint{method: Method::GET, path: "/api/anomalies/{begin}/{end}", description: "Stored prices that jumped suspiciously far from the one before", handler: |r| r.to(anomalies)},
+    ApiEndpoint{method: Method::GET, path: "/api/calendar/{begin}/{end}", description: "Closing price of each day, week or month in a time zone", handler: |r| r.to(calendar)},
+    ApiEndpoint{method: Method::GET, path: "/api/recent/{n}", description: "Latest prices, from memory when possible", handler: |r| r.to(recent)},
+    ApiEndpoint{method: Method::GET, path: "/api/completeness/{begin}/{end}", description: "Stored points per day against the expected number", handler: |r| r.to(completeness)},
+    ApiEndpoint{method: Method::GET, path: "/api/day_count", description: "How many UTC days have stored prices, and the first and last of them", handler: |r| r.to(day_count)},
+    ApiEndpoint{method: Method::GET, path: "/api/freshness", description: "Seconds since the updater last stored a price", handler: |r| r.to(freshness)}
+];
+
+/**
+Registers every endpoint in `API_ENDPOINTS`, for `App::configure`.
+*/
+pub fn configure_api(cfg: &mut web::ServiceConfig)
+{
+    for endpoint in API_ENDPOINTS.iter()
+    {
+        cfg.route(endpoint.path, (endpoint.handler)(web::method(endpoint.method.clone())));
+    }
+}
+
+lazy_static!
+{
+    /// Patterns of the endpoints in API_ENDPOINTS, in the same order
+    static ref API_PATTERNS: Vec<ResourceDef> = API_ENDPOINTS.iter().map(|e| ResourceDef::new(e.path)).collect();
+}
+
+/**
+Finds which endpoint under /api a request goes to, the same way the router does, so requests can be counted by route.
+
+# Parameters
+- `method`: HTTP method of the request
+- `path`: Path of the request
+
+# Returns
+The path pattern of the endpoint, like "/api/prices/{begin}/{end}", or "unknown" for a path under /api that no endpoint takes.
+None for paths outside /api.
+*/
+pub fn api_route(method: &Method, path: &str) -> Option<&'static str>
+{
+    if path != "/api" && !path.starts_with("/api/") {return None;}
+    let found = API_ENDPOINTS.iter().zip(API_PATTERNS.iter()).find(|(e, pattern)| e.method == method && pattern.is_match(path));
+    Some(found.map_or("unknown", |(e, _)| e.path))
+}
+
+/**
+Responds to requests for "/api" with the list of endpoints under it.
+
+# Returns
+HttpResponse containing a JSON array with an object for each endpoint: its `method`, `path` pattern and `description`.
+*/
+pub async fn api_index() -> HttpResponse
+{
+    json_response(StatusCode::OK, endpoint_index(API_ENDPOINTS))
+}
+
+/**
+Lists endpoints as JSON, for the index at /api.
+*/
+fn endpoint_index(endpoints: &[ApiEndpoint]) -> serde_json::Value
+{
+    endpoints.iter().map(|e| json!({"method": e.method.as_str(), "path": e.path, "description": e.description})).collect()
+}
+
+/**
+Decides how to resample a range: over a fixed number of segments, so we can return any range in the same amount of time.
+
+# Parameters
+- `begin`, `end`: The range, in order
+- `segments`: How many segments to split it into, must not be zero
+
+# Returns
+Length of each segment in seconds, at least 1.
+*/
+pub(crate) fn segment_size(begin: u64, end: u64, segments: u64) -> u64
+{
+    cmp::max((end - begin) / segments, 1)
+}
+
+/**
+Drops the segments of a resampled range that only hold the virtual points at the beginning and end of time,
+which pad the range for charting but would throw off any analysis of the prices.
+
+# Parameters
+- `rows`: Tuples of (when, price, synthetic) from the range query
+
+# Returns
+(when, price) of the segments with stored prices, in the same order.
+*/
+pub(crate) fn stored_series(rows: Vec<(u64,u64,bool)>) -> Vec<(u64,u64)>
+{
+    rows.into_iter().filter(|&(_, _, synthetic)| !synthetic).map(|(when, price, _)| (when, price)).collect()
+}
+
+/**
+Downsamples rows with Largest-Triangle-Three-Buckets, picking only from the stored prices.
+The virtual boundary rows are kept as they are, since their times would swamp the triangle areas.
+
+# Parameters
+- `rows`: (when, price, synthetic) of each row, in order of time
+- `n`: How many of the stored rows to keep
+
+# Returns
+The kept stored rows and the virtual rows, in order of time.
+*/
+fn lttb_rows(rows: Vec<(u64,u64,bool)>, n: usize) -> Vec<(u64,u64,bool)>
+{
+    let (mut kept, stored): (Vec<_>, Vec<_>) = rows.into_iter().partition(|&(_, _, synthetic)| synthetic);
+    let series = stored_series(stored);
+    kept.extend(analysis::lttb(&series, n).into_iter().map(|i| (series[i].0, series[i].1, false)));
+    kept.sort_by_key(|&(when, _, _)| when);
+    kept
+}
+
+/**
+Takes a permit to query the database without waiting for one, so a flood of requests is turned away instead of piling up.
+
+# Parameters
+- `permits`: The semaphore limiting concurrent queries
+
+# Returns
+- `Ok`: The permit, which must be held until the query is done
+- `Err`: The response to send back instead: 503 Service Unavailable with a Retry-After header
+*/
+fn db_permit(permits: &Semaphore) -> Result<SemaphorePermit<'_>, HttpResponse>
+{
+    permits.try_acquire().map_err(|_|
+        ResponseBuilder::new(StatusCode::SERVICE_UNAVAILABLE)
+            .set_header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+            .set_header(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())
+            .json("Too many requests are querying the database, try again shortly"))
+}
+
+/**
+Turns away requests for the data endpoints while in maintenance mode, so nothing is served from a database that's being worked on.
+Everything outside /api, like the admin endpoints, the main page and /metrics, keeps answering.
+
+# Parameters
+- `path`: Path of the request
+- `maintenance`: The maintenance flag, normally `MAINTENANCE`
+
+# Returns
+The response to send instead of handling the request: 503 Service Unavailable with a Retry-After header.
+None if the request should be handled as usual.
+*/
+pub fn maintenance_response(path: &str, maintenance: &AtomicBool) -> Option<HttpResponse>
+{
+    if !path.starts_with("/api/") || !maintenance.load(Ordering::Relaxed) {return None;}
+    Some(ResponseBuilder::new(StatusCode::SERVICE_UNAVAILABLE)
+        .set_header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+        .set_header(header::RETRY_AFTER, MAINTENANCE_RETRY_AFTER_SECS.to_string())
+        .json(json!({"maintenance": true, "error": "Down for maintenance, try again later"})))
+}
+
+/**
+Answers a request with 503 Service Unavailable if handling it takes longer than the deadline, so no slow path ties up the client forever.
+
+The deadline can only be noticed while the handler is waiting on something asynchronous. Queries through `sql::fetch_rows` are,
+since they run on the blocking thread pool; one that's cut off still runs to completion there, but its result is thrown away.
+
+# Parameters
+- `handling`: The rest of the app handling the request
+- `timeout_ms`: The deadline in milliseconds. 0 means no deadline.
+- `request`: Method and path of the request, for the log
+
+# Returns
+The response from the rest of the app.
+
+# Errors
+An error that actix sends as the 503 response, with a JSON body describing it, when the deadline passes.
+*/
+pub async fn with_deadline<F, R>(handling: F, timeout_ms: u64, request: String) -> Result<R, Error>
+    where F: Future<Output = Result<R, Error>>
+{
+    if timeout_ms == 0 {return handling.await;}
+    match actix_rt::time::timeout(Duration::from_millis(timeout_ms), handling).await
+    {
+        Ok(res) => res,
+        Err(_) => {
+            warn!("{} took more than {} ms, answered with 503", request, timeout_ms);
+            let resp = json_response(StatusCode::SERVICE_UNAVAILABLE, "The request took too long, try again later");
+            Err(InternalError::from_response("request timed out", resp).into())
+        }
+    }
+}
+
+/// Header a request's ID is taken from and given back in
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request ID kept from a client
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/**
+Picks the ID of a request and puts it in the request's X-Request-Id header, where the access log and handlers can find it.
+
+# Parameters
+- `headers`: Headers of the request
+- `accept_incoming`: Whether to keep an X-Request-Id sent by the client, as configured by `accept_request_id`.
+  It's only kept if it's up to 128 letters, digits, `-`, `_`, `.` or `:`, so it can't garble the logs.
+
+# Returns
+The ID: the client's, or else a new random UUID.
+
+# Examples
+```
+use actix_web::test::TestRequest;
+use bitcoin_trend::pages;
+let req = TestRequest::default().header("X-Request-Id", "lb-4f2a").to_http_request();
+let mut headers = req.headers().clone();
+assert_eq!(pages::tag_request(&mut headers, true), "lb-4f2a");
+assert_ne!(pages::tag_request(&mut headers, false), "lb-4f2a");
+```
+*/
+pub fn tag_request(headers: &mut HeaderMap, accept_incoming: bool) -> String
+{
+    let incoming = headers.get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| accept_incoming && !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c)))
+        .map(str::to_string);
+    let id = incoming.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    if let Ok(value) = HeaderValue::from_str(&id) {headers.insert(header::HeaderName::from_static(REQUEST_ID_HEADER), value);}
+    id
+}
+
+/**
+Handles a request with its ID available to the database layer, see `sql::with_request_id`, and gives the ID back in the response's X-Request-Id header.
+
+# Parameters
+- `handling`: The rest of the app handling the request
+- `id`: The request's ID, from `tag_request`
+
+# Returns
+The response from the rest of the app, with the header added.
+
+# Errors
+Whatever error the rest of the app gave, which goes out without the header.
+*/
+pub async fn with_request_id<F, B>(handling: F, id: String) -> Result<ServiceResponse<B>, Error>
+    where F: Future<Output = Result<ServiceResponse<B>, Error>>
+{
+    let mut res = sql::with_request_id(id.clone(), handling).await?;
+    if let Ok(value) = HeaderValue::from_str(&id) {res.headers_mut().insert(header::HeaderName::from_static(REQUEST_ID_HEADER), value);}
+    Ok(res)
+}
+
+/**
+Makes sure a requested range is in the right order.
+
+# Returns
+- `Ok`: The beginning and end of the range
+- `Err`: The response to send back instead
+*/
+fn check_range(range: &web::Path<(u64, u64)>) -> Result<(u64, u64), HttpResponse>
+{
+    ordered_range(range.0, range.1)
+}
+
+/**
+Same as `check_range`, for a range that didn't come from its own path tuple.
+*/
+fn ordered_range(begin: u64, end: u64) -> Result<(u64, u64), HttpResponse>
+{
+    if end < begin {
+        return Err(json_response(StatusCode::BAD_REQUEST, "begin (first value) must be <= end (second value)"));
+    }
+    Ok((begin, end))
+}
+
+/**
+Builds a response with a JSON body.
+
+# Parameters
+- `status`: HTTP status code of the response
+- `body`: Anything serializable. For errors, this is just a string describing the error.
+
+Missing values should be None. The body is normalized into a `serde_json::Value` first, which turns any float that isn't finite into null,
+so the body is always valid JSON. A body that can't be represented as JSON at all gets a 500 with a JSON error instead.
+*/
+fn json_response<T: Serialize>(status: StatusCode, body: T) -> HttpResponse
+{
+    let (status, body) = match serde_json::to_value(body)
+    {
+        Ok(normalized) => (status, normalized),
+        Err(e) => {
+            error!("Couldn't serialize a response body as JSON: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, json!("Couldn't serialize the response"))
+        }
+    };
+    ResponseBuilder::new(status)
+        .set_header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+        .json(body)
+}
+
+/**
+Answers a request whose URL parameters couldn't be read, like a word where a timestamp goes, with 400 Bad Request
+and a JSON body describing the problem, the same as the handlers' own errors, instead of actix's plain text.
+Set it as the error handler of the app's `PathConfig` so it covers every endpoint with parameters in its path.
+
+# Returns
+An error that actix sends as the 400 response.
+*/
+pub fn path_error(err: PathError, _req: &HttpRequest) -> Error
+{
+    let resp = json_response(StatusCode::BAD_REQUEST, format!("Invalid URL parameters: {}", err));
+    InternalError::from_response(err, resp).into()
+}
+
+/**
+Spreads the per-hour rows from the database over all 24 hours of the day.
+
+# Parameters
+- `rows`: Tuples of (hour, avg_price, count) for the hours that have data
+- `price_scale`: Units per dollar the prices are stored in
+
+# Returns
+One `[hour, avg_price_cents, count]` array for each hour 0 through 23, with null prices and zero counts for hours that had no rows.
+*/
+fn hour_of_day_buckets(rows: Vec<(u64,u64,u64)>, price_scale: u64) -> Vec<serde_json::Value>
+{
+    (0..24u64).map(|hour|
+        match rows.iter().find(|row| row.0 == hour)
+        {
+            Some(&(_, price, count)) => json!([hour, cents_json(price as i64, price_scale), count]),
+            None => json!([hour, null, 0])
+        }
+    ).collect()
+}
+
+/**
+Converts a price or price difference as stored, in units set by `price_scale`, to the cents sent to clients.
+
+# Parameters
+- `stored`: The value as stored
+- `price_scale`: Units per dollar the value is stored in
+
+# Returns
+The value in cents: an integer when stored as cents, so the output is unchanged for the default scale, otherwise a fractional number.
+*/
+pub(crate) fn cents_json(stored: i64, price_scale: u64) -> serde_json::Value
+{
+    if price_scale == 100 {json!(stored)} else {json!(stored as f64 * 100.0 / price_scale as f64)}
+}
+
+/**
+Looks up the keys to use for each point of the prices endpoint, as chosen by its "fields" parameter.
+
+# Parameters
+- `fields`: Value of the "fields" query string parameter, if given
+
+# Returns
+- `None`: The value isn't one we know.
+- `Some(None)`: No value; points are sent as arrays.
+- `Some(Some(names))`: Keys for the time, price and synthetic flag of each point.
+  "long" gives `when`/`avg_price_cents`/`synthetic`, "short" gives `t`/`v`/`s`.
+*/
+fn field_names(fields: Option<&str>) -> Option<Option<[&'static str; 3]>>
+{
+    match fields
+    {
+        None => Some(None),
+        Some("long") => Some(Some(["when", "avg_price_cents", "synthetic"])),
+        Some("short") => Some(Some(["t", "v", "s"])),
+        Some(_) => None
+    }
+}
+
+/**
+Turns the rows of a resampled range into the JSON points sent to clients, filling gaps and collapsing runs first if asked to.
+
+# Parameters
+- `rows`: Tuples of (when, price, synthetic), where the price is in cents or has been rescaled
+- `segment_size`: Length of each segment in seconds
+- `fill_range`: The range to add a point with a null price for each empty segment of, see `fill_gaps`, or None to leave gaps as they are
+- `dedup`: Whether to collapse runs of equal prices, see `collapse_runs`
+- `mark_synthetic`, `iso_time`, `names`: As for `price_points_json`
+*/
+fn finish_points<P: Serialize + PartialEq>(rows: Vec<(u64,P,bool)>, segment_size: u64, fill_range: Option<(u64,u64)>, dedup: bool, mark_synthetic: bool, iso_time: bool, names: Option<[&str; 3]>) -> Vec<serde_json::Value>
+{
+    let rows = if let Some((begin, end)) = fill_range {fill_gaps(rows, segment_size, begin, end, &TRADING_WINDOWS)} else {rows.into_iter().map(|(when, price, synthetic)| (when, Some(price), synthetic)).collect()};
+    let rows = if dedup {collapse_runs(rows)} else {rows};
+    price_points_json(rows, mark_synthetic, iso_time, names)
+}
+
+/**
+Drops the points in the middle of each run of consecutive points with the same price, keeping the first and last of the run.
+A step chart drawn from what's left looks the same. Empty segments filled with no price count as equal to each other.
+
+# Parameters
+- `rows`: Tuples of (when, price, synthetic) in time order
+
+# Returns
+The rows that are left, in time order.
+*/
+fn collapse_runs<P: PartialEq>(rows: Vec<(u64,P,bool)>) -> Vec<(u64,P,bool)>
+{
+    let mut kept: Vec<(u64,P,bool)> = Vec::with_capacity(rows.len());
+    for row in rows
+    {
+        //a third equal price in a row makes the one before it a middle point
+        let len = kept.len();
+        if len >= 2 && kept[len - 1].1 == row.1 && kept[len - 2].1 == row.1 {kept.pop();}
+        kept.push(row);
+    }
+    kept
+}
 
 /**
-Responds to requests for the main page at the domain root.
+Adds a point with no price at each segment boundary between the rows of a resampled range that has no data of its own.
+
+Only the segments between two stored points are filled, and only within the range asked for.
+The virtual points at the beginning and end of time, and the stretches between them and the stored points, are left alone.
+
+# Parameters
+- `rows`: Tuples of (when, price, synthetic) from the range query, where each `when` is a multiple of `segment_size`
+- `segment_size`: Length of each segment in seconds
+- `begin`, `end`: The range asked for
+- `trading_windows`: When the market is open. Segments starting while it's closed aren't missing data, so they aren't filled.
 
 # Returns
-HttpResponse containing the main page, which is the same every time -- everything dynamic is in the frontend code.
+The same rows with the price wrapped in Some, with (when, None, false) added for each empty segment, in time order.
 */
-pub async fn index() -> HttpResponse
+fn fill_gaps<P>(rows: Vec<(u64,P,bool)>, segment_size: u64, begin: u64, end: u64, trading_windows: &[TradingWindow]) -> Vec<(u64,Option<P>,bool)>
 {
-    let body = "<div id='price_chart_container'><canvas id='price_chart'></canvas></div><br/><div id='slider'></div><br/><span id='begin'></span> - <span id='end'></span><img src='static/loading.gif' id='spinner'/>";
-    let head = "<script>$( function() {chart_init();});</script>";
+    let segment_size = cmp::max(segment_size, 1);
+    let first_segment = begin - begin % segment_size;
+    let mut filled = Vec::with_capacity(rows.len());
+    let mut next_expected: Option<u64> = None;
+    for (when, price, synthetic) in rows
+    {
+        if let (Some(start), false) = (next_expected, synthetic)
+        {
+            let mut gap = Some(cmp::max(start, first_segment));
+            while let Some(g) = gap.filter(|&g| g < when && g <= end)
+            {
+                if trading_hours::is_open(trading_windows, g as i64) {filled.push((g, None, false));}
+                gap = g.checked_add(segment_size);
+            }
+        }
+        filled.push((when, Some(price), synthetic));
+        next_expected = if synthetic {None} else {when.checked_add(segment_size)};
+    }
+    filled
+}
 
-    let html = html_construct("Home - Bitcoin Trend", head, body);
+/**
+Converts the rows returned by the range query into the JSON sent to clients.
 
-    ResponseBuilder::new(StatusCode::OK)
-        .set_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
-        .body(html)
+# Parameters
+- `rows`: Tuples of (when, price, synthetic) from the range query, with a None price for filled gaps
+- `mark_synthetic`: Whether to include the synthetic flag as a third element of each point
+- `iso_time`: Whether to send each time as an RFC 3339 string, see `time_json`, instead of Unix seconds
+- `names`: Keys to send each point as an object with, instead of an array, as given by `field_names`
+
+# Returns
+One JSON array per point: `[when, avg_price_cents]` or `[when, avg_price_cents, synthetic]`,
+or an object with the same values under the given keys.
+*/
+fn price_points_json<P: Serialize>(rows: Vec<(u64,Option<P>,bool)>, mark_synthetic: bool, iso_time: bool, names: Option<[&str; 3]>) -> Vec<serde_json::Value>
+{
+    rows.into_iter()
+        .map(|(when, price, synthetic)| {
+            let when = time_json(when, iso_time);
+            match names
+            {
+                None => if mark_synthetic {json!([when, price, synthetic])} else {json!([when, price])},
+                Some([when_key, price_key, synthetic_key]) => {
+                    let mut point = serde_json::Map::new();
+                    point.insert(when_key.to_string(), when);
+                    point.insert(price_key.to_string(), json!(price));
+                    if mark_synthetic {point.insert(synthetic_key.to_string(), json!(synthetic));}
+                    serde_json::Value::Object(point)
+                }
+            }
+        })
+        .collect()
 }
 
 /**
-Responds to requests for the api endpoint "prices"
+Converts a Unix timestamp to the JSON sent to clients.
 
 # Parameters
-- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+- `when`: The timestamp
+- `iso`: Whether to send it as an RFC 3339 string in UTC with whole seconds, instead of as a number
 
 # Returns
-HttpResponse containing (if successful) JSON with the requested data.
+The number, or the string. A time too far out for a date is sent as the number either way.
+*/
+fn time_json(when: u64, iso: bool) -> serde_json::Value
+{
+    if !iso {return json!(when);}
+    match std::convert::TryFrom::try_from(when).ok().and_then(|t| Utc.timestamp_opt(t, 0).single())
+    {
+        Some(t) => json!(t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        None => json!(when)
+    }
+}
 
-# Errors
-The HttpResponse can also indicate failure, which happens when anything goes wrong like
-invalid input or a database error. In this case the body will still be JSON, but it will
-only contain a string describing the error.
+/**
+Formats points as CSV, under a header naming the columns.
+
+# Parameters
+- `points`: (when, price) points, with the price already in cents
+
+# Returns
+One string per line, each ending with a newline: the header `when,price_cents`, then one per point.
 */
-pub async fn api(range: web::Path<(u64, u64)>) -> HttpResponse
+pub(crate) fn csv_lines<I: IntoIterator<Item = (u64, serde_json::Value)>>(points: I) -> Vec<String>
 {
-    let mut db = match sql::connect(){
-        Ok(d) => d,
-        Err(e) => {
-            let e_str = format!("Database error: {}",e);
-            return ResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR)
-                .set_header(header::CONTENT_TYPE, "application/json; charset=utf-8")
-                .json(e_str);
-        }
-    };
-    let begin = range.0;
-    let end = range.1;
-    let segment_size = cmp::max((end - begin) / 100, 1);
+    let mut lines = vec![String::from("when,price_cents\n")];
+    lines.extend(points.into_iter().map(|(when, cents)| format!("{},{}\n", when, cents)));
+    lines
+}
 
-    if end < begin {
-        return ResponseBuilder::new(StatusCode::BAD_REQUEST)
-        .set_header(header::CONTENT_TYPE, "application/json; charset=utf-8")
-        .json("begin (first value) must be <= end (second value)");
-    }
-
-    /* Get prices for the range specified.
-    - If there isn't a data point exactly on the given begin/end points, use the closest value outside the range. (COALESCE with subquery)
-      - Support this by including virtual data points at the beginning and end of time that match the closest values (FROM UNION)
-    - Resample the data over 100 segments so we can return any range in the same amount of time. (GROUP BY `when` DIV segment_size)
-    */
-    let range_query = "
-SELECT 
-    `segment_num` * ? AS `when`,
-    `avg_price_cents` AS avg_price_cents
-FROM(
-	SELECT
-		FLOOR(`when` DIV ?) AS segment_num,
-		FLOOR(AVG(`price_cents`))  AS avg_price_cents
-	FROM(
-		SELECT * FROM `price_history`
-		UNION SELECT 0,439
-		UNION SELECT
-			~0,
-			(
-				SELECT `price_cents`
-				FROM `price_history`
-				WHERE `when`=(SELECT MAX(`when`) FROM `price_history`)
-			)
-	) AS prices
-	WHERE `when` >= COALESCE((SELECT MAX(`when`) FROM `price_history` WHERE `when` <= ?), 0)
-		AND `when` <= COALESCE((SELECT MIN(`when`) FROM `price_history` WHERE `when` >= ?), ~0)
-	GROUP BY `segment_num`
-) AS segmented_averages
-ORDER BY `when`
-    ".replace("\n"," ").replace("\r"," ");
-
-    let prices = match sql::query_select::<(u64,u64,u64,u64),(u64,u32)>(&mut db, &range_query, (segment_size, segment_size, begin, end), "getting price data for range")
-    {
-        Err(e) => {
-            let e_str = format!("Database error: {}",e);
-            return ResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR)
-                .set_header(header::CONTENT_TYPE, "application/json; charset=utf-8")
-                .json(e_str);
-        },
-        Ok(r) => r
-    };
+/**
+Serializes points as newline-delimited JSON.
 
-    ResponseBuilder::new(StatusCode::OK)
-        .set_header(header::CONTENT_TYPE, "application/json; charset=utf-8")
-        .json(prices)
+# Returns
+One string per point, each holding the point's JSON followed by a newline.
+*/
+fn ndjson_lines(points: Vec<serde_json::Value>) -> Vec<String>
+{
+    points.into_iter().map(|point| format!("{}\n", point)).collect()
 }
 
 /**
@@ -113,13 +1957,99 @@ HttpResponse indicating HTTP 404 Not Found.
 */
 pub async fn notfound() -> HttpResponse
 {
-    let html = html_construct("Not Found - Bitcoin Trend", "", "<h1>Not Found</h1><a href='/'>Return to Home</a>");
+    let html = html_construct("Not Found - Bitcoin Trend", "", "<h1>Not Found</h1><a href='/'>Return to Home</a>", &ASSET_VERSIONS);
 
     ResponseBuilder::new(StatusCode::NOT_FOUND)
         .set_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
         .body(html)
 }
 
+/**
+Works out the IP address of the client that made a request.
+
+# Parameters
+- `headers`: Headers of the request
+- `peer_addr`: Address of the peer that connected to us, if known
+- `trusted_proxy`: Whether the request came through a reverse proxy whose X-Forwarded-For/X-Real-IP headers can be believed.
+  When false they are ignored, since any client could send them.
+
+# Returns
+The last address in X-Forwarded-For, which is the one the proxy added, else the one in X-Real-IP, if trusted and valid.
+Any addresses before it came from the client, so could be forged.
+Otherwise the address of the peer that connected to us, or None if that isn't known.
+*/
+pub fn client_ip(headers: &HeaderMap, peer_addr: Option<SocketAddr>, trusted_proxy: bool) -> Option<IpAddr>
+{
+    let header_ip = |name: &str| headers.get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .and_then(|v| v.trim().parse::<IpAddr>().ok());
+
+    if trusted_proxy
+    {
+        if let Some(ip) = header_ip("X-Forwarded-For").or_else(|| header_ip("X-Real-IP")) {return Some(ip);}
+    }
+    peer_addr.map(|addr| addr.ip())
+}
+
+/// Static files served from this app that the generated pages link to
+const LOCAL_ASSETS: [&str; 4] = ["static/favicon.ico", "static/loading.gif", "static/main.js", "static/main.css"];
+
+lazy_static!
+{
+    /// Cache-busting versions of the local static files, by path
+    static ref ASSET_VERSIONS: HashMap<String, String> = asset_versions(&LOCAL_ASSETS, SETTINGS.http.asset_version);
+}
+
+/**
+Works out the version to put in the URL of each static file, so it changes whenever the file might have.
+
+# Parameters
+- `paths`: The files, relative to the working dir
+- `mode`: Where the versions come from, as configured by `asset_version`
+
+# Returns
+The version of each file, by path. Files whose version can't be found are left out, and so are linked without one.
+*/
+fn asset_versions(paths: &[&str], mode: AssetVersion) -> HashMap<String, String>
+{
+    paths.iter().filter_map(|&path| {
+        let version = match mode
+        {
+            AssetVersion::Off => None,
+            AssetVersion::CrateVersion => Some(String::from(env!("CARGO_PKG_VERSION"))),
+            AssetVersion::Mtime => match fs::metadata(path).and_then(|m| m.modified())
+            {
+                Ok(modified) => modified.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs().to_string()),
+                Err(e) => {warn!("Couldn't read the modification time of {} for its URL, linking it without a version: {}", path, e); None}
+            }
+        };
+        version.map(|v| (String::from(path), v))
+    }).collect()
+}
+
+/**
+The URL to link a static file with, including its version if it has one.
+
+# Examples
+```
+use bitcoin_trend::pages;
+use std::collections::HashMap;
+let mut versions = HashMap::new();
+versions.insert(String::from("static/main.js"), String::from("1590000000"));
+assert_eq!(pages::asset_url("static/main.js", &versions), "static/main.js?v=1590000000");
+assert_eq!(pages::asset_url("static/main.css", &versions), "static/main.css");
+```
+*/
+pub fn asset_url(path: &str, versions: &HashMap<String, String>) -> String
+{
+    match versions.get(path)
+    {
+        Some(v) => format!("{}?v={}", path, v),
+        None => String::from(path)
+    }
+}
+
 /**
 Generates a complete HTML document given the elements that change between pages.
 This is where we define all the external static resources included in every page, and other HTML boilerplate.
@@ -128,11 +2058,12 @@ This is where we define all the external static resources included in every page
 - `title`: The contents of the title tag, which browsers tend to display in their title bar
 - `head_extra`: HTML content to be included in the root of the head tag, intended for page-specific styles/scripts
 - `body`: contents of the body tag
+- `versions`: Cache-busting versions of the local static files, from `asset_versions`
 
 # Returns
-String containing the HTML document.None
+String containing the HTML document.
 */
-fn html_construct(title: &str, head_extra: &str, body: &str) -> String
+fn html_construct(title: &str, head_extra: &str, body: &str, versions: &HashMap<String, String>) -> String
 {
     format!("<!DOCTYPE html>
 <html>
@@ -140,14 +2071,14 @@ fn html_construct(title: &str, head_extra: &str, body: &str) -> String
   <meta charset='utf-8'/>
   <meta http-equiv='X-UA-Compatible' content='IE=edge'/>
   <meta name='viewport' content='height=device-height, width=device-width, initial-scale=1'/>
-  <link rel='shortcut icon' href='static/favicon.ico'/>
+  <link rel='shortcut icon' href='{}'/>
   <script src='https://unpkg.com/jquery@3.5.1/dist/jquery.min.js'></script>
   <link rel='stylesheet' href='https://code.jquery.com/ui/1.12.1/themes/base/jquery-ui.css'/>
   <script src='https://code.jquery.com/ui/1.12.1/jquery-ui.min.js' integrity='sha256-VazP97ZCwtekAsvgPBSUwPFKdrwD3unUfSGVYrahUqU=' crossorigin='anonymous'></script>
   <script src='https://unpkg.com/moment@2.19.3/min/moment-with-locales.min.js'></script>
   <script src='https://unpkg.com/chart.js@2.7.1/dist/Chart.min.js'></script>
-  <script src='static/main.js'></script>
-  <link rel='stylesheet' href='static/main.css'/>
+  <script src='{}'></script>
+  <link rel='stylesheet' href='{}'/>
   {}
   <title>{}</title>
  </head>
@@ -155,6 +2086,7 @@ fn html_construct(title: &str, head_extra: &str, body: &str) -> String
  {}
  </body>
 </html>",
+    asset_url("static/favicon.ico", versions), asset_url("static/main.js", versions), asset_url("static/main.css", versions),
     head_extra, title, body)
 }
 
@@ -167,13 +2099,684 @@ example usage in the docs, but are still possible to unit-test
 mod tests
 {
     use super::*;
+    use crate::settings::Settings;
+    use std::env;
 
 	// html_construct
 	#[test]
 	fn gen_page()
 	{
-        let html = html_construct("Not Found", "", "<h1>Not Found</h1><a href='/'>Return to Home</a>");
+        let html = html_construct("Not Found", "", "<h1>Not Found</h1><a href='/'>Return to Home</a>", &HashMap::new());
         assert_eq!(&html[..15],"<!DOCTYPE html>");
     }
 
-}
\ No newline at end of file
+    // index_html
+    #[test]
+    fn empty_database_page()
+    {
+        let versions = HashMap::new();
+        let populated = index_html(true, None, &versions);
+        let empty = index_html(false, None, &versions);
+        assert_ne!(populated, empty);
+
+        assert!(populated.contains("<canvas id='price_chart'>"));
+        assert!(populated.contains("chart_init();"));
+        //nothing left to spin or query the API
+        assert!(empty.contains("Collecting data"));
+        assert!(!empty.contains("price_chart"));
+        assert!(!empty.contains("chart_init();"));
+    }
+
+    // index_html
+    #[test]
+    fn embedded_initial_data()
+    {
+        let versions = HashMap::new();
+        let points = json!([[1325347200, 441], [1325433600, 460]]);
+        let embedded = index_html(true, Some(&points), &versions);
+        assert!(embedded.contains("<script>var initial_prices = [[1325347200,441],[1325433600,460]];</script>"));
+        //the data is there before the chart starts
+        assert!(embedded.find("initial_prices").unwrap() < embedded.find("chart_init();").unwrap());
+
+        assert!(!index_html(true, None, &versions).contains("initial_prices"));
+        let sneaky = index_html(true, Some(&json!(["</script><script>alert(1)"])), &versions);
+        assert!(!sneaky.contains("</script><script>alert"));
+    }
+
+    // html_construct, asset_versions
+    #[test]
+    fn asset_version_query()
+    {
+        let versions = asset_versions(&LOCAL_ASSETS, AssetVersion::CrateVersion);
+        let html = html_construct("Home", "", "", &versions);
+        let version = env!("CARGO_PKG_VERSION");
+        assert!(html.contains(&format!("<script src='static/main.js?v={}'></script>", version)));
+        assert!(html.contains(&format!("href='static/main.css?v={}'", version)));
+        assert!(html.contains(&format!("href='static/favicon.ico?v={}'", version)));
+        //third party URLs are left alone
+        assert!(html.contains("<script src='https://unpkg.com/chart.js@2.7.1/dist/Chart.min.js'></script>"));
+
+        let path = env::temp_dir().join(format!("bitcoin_trend_asset_test_{}.js", std::process::id()));
+        fs::write(&path, "chart_init();").unwrap();
+        let path_str = path.to_str().unwrap();
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let versions = asset_versions(&[path_str, "no/such/file.js"], AssetVersion::Mtime);
+        assert_eq!(asset_url(path_str, &versions), format!("{}?v={}", path_str, mtime));
+        assert_eq!(asset_url("no/such/file.js", &versions), "no/such/file.js");
+        fs::remove_file(&path).unwrap();
+
+        assert!(!html_construct("Home", "", "", &asset_versions(&LOCAL_ASSETS, AssetVersion::Off)).contains("?v="));
+    }
+
+    // cache_control
+    #[test]
+    fn cache_age()
+    {
+        let http = Settings::from_toml("[http]\nrecent_window_secs = 7200\nrecent_max_age_secs = 60\nhistorical_max_age_secs = 86400\n").unwrap().http;
+        let now = 1590000000;
+
+        assert_eq!(cache_control(1325347200, now, &http), "public, max-age=86400");
+        assert_eq!(cache_control(now as u64, now, &http), "public, max-age=60");
+        assert_eq!(cache_control(now as u64 - 3600, now, &http), "public, max-age=60");
+        assert_eq!(cache_control(u64::MAX, now, &http), "public, max-age=60");
+
+        let uncached = Http{recent_max_age_secs: 0, ..http};
+        assert_eq!(cache_control(now as u64, now, &uncached), "no-cache");
+    }
+
+    // price_points_json
+    #[test]
+    fn synthetic_flags()
+    {
+        //A range beginning before the data snaps to the virtual point at time 0
+        let rows = vec![(0, Some(439), true), (1325347200, Some(441), false), (1325433600, Some(460), false)];
+
+        let plain = price_points_json(rows.clone(), false, false, None);
+        assert_eq!(json!(plain), json!([[0, 439], [1325347200, 441], [1325433600, 460]]));
+
+        let marked = price_points_json(rows, true, false, None);
+        assert_eq!(json!(marked), json!([[0, 439, true], [1325347200, 441, false], [1325433600, 460, false]]));
+    }
+
+    // fill_gaps
+    #[test]
+    fn gaps_filled()
+    {
+        //segments at 200 and 300 have no data
+        let rows = vec![(0, 439, true), (100, 441, false), (400, 460, false), (500, 455, false)];
+        let filled = fill_gaps(rows, 100, 0, 500, &[]);
+        assert_eq!(filled, vec![
+            (0, Some(439), true), (100, Some(441), false), (200, None, false),
+            (300, None, false), (400, Some(460), false), (500, Some(455), false)
+        ]);
+        assert_eq!(json!(price_points_json(filled, false, false, None))[2], json!([200, null]));
+
+        //nothing to fill without a gap
+        assert_eq!(fill_gaps(vec![(0, 1, false), (10, 2, false)], 10, 0, 10, &[]).len(), 2);
+        assert!(fill_gaps::<u64>(vec![], 10, 0, 10, &[]).is_empty());
+
+        //nothing is filled out to the virtual points, or outside the range asked for
+        let rows = vec![(0, 439, true), (1586300000, 441, false), (1586300400, 460, false), (1586301000, 455, false), (u64::MAX - 15, 455, true)];
+        let filled = fill_gaps(rows, 100, 1586300000, 1586300700, &[]);
+        let times: Vec<u64> = filled.iter().map(|p| p.0).collect();
+        assert_eq!(times, vec![0, 1586300000, 1586300100, 1586300200, 1586300300, 1586300400, 1586300500, 1586300600, 1586300700, 1586301000, u64::MAX - 15]);
+        assert_eq!(filled.iter().filter(|p| p.1.is_none()).count(), 6);
+    }
+
+    // price_points_json, time_json
+    #[test]
+    fn iso_times()
+    {
+        let rows = vec![(1586347200, Some(689012), false), (1586350800, None, false)];
+        let points = price_points_json(rows.clone(), false, true, None);
+        assert_eq!(points, vec![json!(["2020-04-08T12:00:00Z", 689012]), json!(["2020-04-08T13:00:00Z", null])]);
+        let named = price_points_json(rows.clone(), false, true, field_names(Some("short")).unwrap());
+        assert_eq!(named[0], json!({"t": "2020-04-08T12:00:00Z", "v": 689012}));
+        //epoch seconds unless asked
+        assert_eq!(price_points_json(rows, false, false, None)[0], json!([1586347200, 689012]));
+
+        assert_eq!(time_json(0, true), json!("1970-01-01T00:00:00Z"));
+        assert_eq!(time_json(u64::MAX, true), json!(u64::MAX));
+    }
+
+    // warming_up
+    #[actix_rt::test]
+    async fn warm_up_threshold()
+    {
+        let warmed_up = AtomicBool::new(false);
+        let resp = warming_up(1000, &warmed_up, || async {Ok(240)}).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = actix_web::test::read_body(actix_web::test::TestRequest::default().to_srv_response(resp)).await;
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), json!({"status": "warming_up", "have": 240, "need": 1000}));
+        assert!(!warmed_up.load(Ordering::Relaxed));
+
+        //a failed count doesn't hold the data back
+        assert!(warming_up(1000, &warmed_up, || async {Err(String::from("gone away"))}).await.is_none());
+
+        assert!(warming_up(1000, &warmed_up, || async {Ok(1000)}).await.is_none());
+        assert!(warmed_up.load(Ordering::Relaxed));
+        //not counted again once there were enough
+        assert!(warming_up(1000, &warmed_up, || async {Ok(0)}).await.is_none());
+        //off by default
+        assert!(warming_up(0, &AtomicBool::new(false), || async {Ok(0)}).await.is_none());
+    }
+
+    // json_response
+    #[actix_rt::test]
+    async fn missing_prices_are_null()
+    {
+        //a missing segment, and averages that came out undefined
+        let filled = fill_gaps(vec![(0, 439.5, false), (200, f64::NAN, false), (300, f64::INFINITY, false)], 100, 0, 300, &[]);
+        let body_of = |resp| actix_web::test::read_body(actix_web::test::TestRequest::default().to_srv_response(resp));
+        let body = body_of(json_response(StatusCode::OK, price_points_json(filled, false, false, None))).await;
+        assert_eq!(&body[..], &b"[[0,439.5],[100,null],[200,null],[300,null]]"[..]);
+        assert!(serde_json::from_slice::<serde_json::Value>(&body).is_ok());
+
+        let body = body_of(json_response(StatusCode::OK, vec![Some(f64::NEG_INFINITY), None])).await;
+        assert_eq!(&body[..], &b"[null,null]"[..]);
+
+        //a map keyed by something JSON can't use as a key
+        let mut unkeyable = std::collections::BTreeMap::new();
+        unkeyable.insert((1, 2), 3);
+        let resp = json_response(StatusCode::OK, unkeyable);
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(&body_of(resp).await[..], &b"\"Couldn't serialize the response\""[..]);
+    }
+
+    // batch_results
+    #[actix_rt::test]
+    async fn batch_ranges()
+    {
+        let ranges: Vec<BatchRange> = serde_json::from_str(r#"[
+            {"begin": 1000, "end": 2000, "segments": 10},
+            {"begin": 5000, "end": 9000},
+            {"begin": 9000, "end": 5000},
+            {"begin": 0, "end": 10, "segments": 0}
+        ]"#).unwrap();
+
+        //stands in for the database: one row per range, at its beginning, priced by segment size
+        let results = batch_results(&ranges, 100, |begin, _end, segment_size| async move {
+            Ok(vec![(begin, segment_size, false)])
+        }).await;
+
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0], json!({"data": [[1000, 100]]}));
+        assert_eq!(results[1], json!({"data": [[5000, 40]]}));
+        assert!(results[2]["error"].is_string());
+        assert!(results[3]["error"].is_string());
+
+        let failing = batch_results(&ranges[..1], 100, |_, _, _| async {Err(String::from("gone away"))}).await;
+        assert_eq!(failing[0], json!({"error": "Database error: gone away"}));
+    }
+
+    // recent_points
+    #[actix_rt::test]
+    async fn recent_from_buffer()
+    {
+        use std::cell::Cell;
+
+        let buffer = Mutex::new(RecentPrices::new(3));
+        let queries = Cell::new(0);
+        let database = |n: usize| {
+            queries.set(queries.get() + 1);
+            async move {Ok::<_, String>((0..n as u64).map(|i| (i, 1)).collect())}
+        };
+
+        //not seeded yet, so the database is asked
+        assert_eq!(recent_points(&buffer, 2, &database).await.unwrap().len(), 2);
+        assert_eq!(queries.get(), 1);
+
+        buffer.lock().unwrap().seed(vec![(100, 5), (200, 6), (300, 7)]);
+        buffer.lock().unwrap().record(400, 8);
+        assert_eq!(recent_points(&buffer, 2, &database).await.unwrap(), vec![(300, 7), (400, 8)]);
+        assert_eq!(recent_points(&buffer, 3, &database).await.unwrap(), vec![(200, 6), (300, 7), (400, 8)]);
+        assert_eq!(queries.get(), 1);
+
+        //more than the buffer holds
+        assert_eq!(recent_points(&buffer, 4, &database).await.unwrap().len(), 4);
+        assert_eq!(queries.get(), 2);
+    }
+
+    // endpoint_index
+    #[test]
+    fn api_endpoint_index()
+    {
+        let index = endpoint_index(API_ENDPOINTS);
+        let listed: Vec<(&str, &str)> = index.as_array().unwrap().iter().map(|e| (e["method"].as_str().unwrap(), e["path"].as_str().unwrap())).collect();
+        assert_eq!(listed.len(), API_ENDPOINTS.len());
+        assert!(listed.contains(&("GET", "/api/prices/{begin}/{end}")));
+        assert!(listed.contains(&("POST", "/api/prices/batch")));
+        assert!(listed.contains(&("GET", "/api")));
+        assert!(listed.contains(&("GET", "/api/freshness")));
+        for endpoint in index.as_array().unwrap()
+        {
+            assert!(endpoint["path"].as_str().unwrap().starts_with("/api"));
+            assert!(!endpoint["description"].as_str().unwrap().is_empty());
+        }
+        //each registered once
+        let mut unique = listed.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), listed.len());
+    }
+
+    // configure_api
+    #[actix_rt::test]
+    async fn api_index_registered()
+    {
+        use actix_web::{test, App};
+        let mut app = test::init_service(App::new().configure(configure_api)).await;
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let index: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(index, endpoint_index(API_ENDPOINTS));
+
+        //"raw" is taken by its own endpoint rather than as a pair, and ".csv" by its own rather than as part of the end
+        for uri in &["/api/prices/raw/20/10", "/api/prices/20/10.csv"]
+        {
+            let resp = test::call_service(&mut app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+            let error: serde_json::Value = serde_json::from_slice(&actix_web::test::read_body(resp).await).unwrap();
+            assert_eq!(error, "begin (first value) must be <= end (second value)", "{}", uri);
+        }
+    }
+
+    // api_route
+    #[test]
+    fn api_routes()
+    {
+        assert_eq!(api_route(&Method::GET, "/api"), Some("/api"));
+        assert_eq!(api_route(&Method::GET, "/api/prices/1586300000/1586400000"), Some("/api/prices/{begin}/{end}"));
+        assert_eq!(api_route(&Method::GET, "/api/prices/raw/1586300000/1586400000"), Some("/api/prices/raw/{begin}/{end}"));
+        assert_eq!(api_route(&Method::GET, "/api/prices/1586300000/1586400000.csv"), Some("/api/prices/{begin}/{end:\\d+}.csv"));
+        assert_eq!(api_route(&Method::POST, "/api/prices/batch"), Some("/api/prices/batch"));
+        assert_eq!(api_route(&Method::GET, "/api/nothing_here"), Some("unknown"));
+        assert_eq!(api_route(&Method::GET, "/metrics"), None);
+        assert_eq!(api_route(&Method::GET, "/apiary"), None);
+    }
+
+    // stored_series
+    #[test]
+    fn boundary_rows_dropped()
+    {
+        let rows = vec![(0, 439, true), (100, 200, false), (110, 220, false), (120, 240, false), (u64::MAX - 15, 240, true)];
+        let series = stored_series(rows);
+        assert_eq!(series, vec![(100, 200), (110, 220), (120, 240)]);
+
+        //the trend of the stored prices, not one flattened by a point at the end of time
+        let line = analysis::trend_line(&series, 100, 130).unwrap();
+        assert_eq!((line.slope, line.intercept, line.r_squared), (2.0, 200.0, Some(1.0)));
+    }
+
+    // lttb_rows
+    #[test]
+    fn lttb_skips_boundary_rows()
+    {
+        let mut rows: Vec<(u64,u64,bool)> = (0..100).map(|i| (1590000000 + i * 60, 20000 + (i % 7) * 10, false)).collect();
+        rows[40].1 = 30000;
+        rows.insert(0, (0, 439, true));
+        rows.push((u64::MAX - 15, 20060, true));
+
+        let kept = lttb_rows(rows, 5);
+        assert_eq!(kept.len(), 7);
+        assert_eq!((kept[0], kept[6]), ((0, 439, true), (u64::MAX - 15, 20060, true)));
+        //the ends of the stored prices are kept, and the spike is found
+        assert_eq!((kept[1].0, kept[5].0), (1590000000, 1590000000 + 99 * 60));
+        assert!(kept.contains(&(1590000000 + 40 * 60, 30000, false)));
+    }
+
+    // biggest_moves
+    #[test]
+    fn boundary_rows_not_moves()
+    {
+        let rows = vec![(0, 439, true), (100, 20000, false), (110, 20100, false), (120, 19900, false), (u64::MAX - 15, 19900, true)];
+        let moves = analysis::biggest_moves(&stored_series(rows), 10, analysis::MoveDirection::Both);
+        assert_eq!(moves.iter().map(|&(when, delta, _)| (when, delta)).collect::<Vec<_>>(), vec![(120, -200), (110, 100)]);
+    }
+
+    // completeness
+    #[actix_rt::test]
+    async fn completeness_days_capped()
+    {
+        use actix_web::{test, App};
+        let mut app = test::init_service(App::new().configure(configure_api)).await;
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/api/completeness/0/18446744073709551615").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let error: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(error, format!("range can cover at most {} days", MAX_COMPLETENESS_DAYS));
+    }
+
+    // path_error
+    #[actix_rt::test]
+    async fn bad_path_parameters()
+    {
+        use actix_web::{test, App};
+        let mut app = test::init_service(App::new()
+            .app_data(web::PathConfig::default().error_handler(path_error))
+            .configure(configure_api)).await;
+        for uri in &["/api/prices/yesterday/1586304000", "/api/drawdown/1325347200/-5", "/api/recent/ten"]
+        {
+            let resp = test::call_service(&mut app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+            assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "application/json; charset=utf-8");
+            let error: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+            assert!(error.as_str().unwrap().starts_with("Invalid URL parameters"), "{}: {}", uri, error);
+        }
+    }
+
+    // collapse_runs
+    #[test]
+    fn flat_runs_collapsed()
+    {
+        let rows = vec![(0, 5, false), (1, 7, false), (2, 7, false), (3, 7, false), (4, 7, false), (5, 8, false), (6, 8, false), (7, 7, false)];
+        let collapsed: Vec<(u64, u64)> = collapse_runs(rows).into_iter().map(|(when, price, _)| (when, price)).collect();
+        //the run of 7s keeps its ends, a run of two has nothing in the middle, and a lone point is untouched
+        assert_eq!(collapsed, vec![(0, 5), (1, 7), (4, 7), (5, 8), (6, 8), (7, 7)]);
+
+        //filled gaps collapse too
+        let filled = vec![(0, Some(5), false), (10, None, false), (20, None, false), (30, None, false), (40, Some(5), false)];
+        let whens: Vec<u64> = collapse_runs(filled).into_iter().map(|(when, _, _)| when).collect();
+        assert_eq!(whens, vec![0, 10, 30, 40]);
+
+        let empty: Vec<(u64, u64, bool)> = vec![];
+        assert!(collapse_runs(empty).is_empty());
+    }
+
+    // with_deadline
+    #[actix_rt::test]
+    async fn request_deadline()
+    {
+        let slow = || async {
+            actix_rt::time::delay_for(Duration::from_millis(500)).await;
+            Ok::<_, Error>(HttpResponse::Ok().finish())
+        };
+        let request = || String::from("GET /api/prices/1586300000/1586400000");
+
+        let e = with_deadline(slow(), 20, request()).await.unwrap_err();
+        let resp = e.as_response_error().error_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "application/json; charset=utf-8");
+
+        //in time, or with no deadline
+        assert_eq!(with_deadline(slow(), 5000, request()).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(with_deadline(slow(), 0, request()).await.unwrap().status(), StatusCode::OK);
+    }
+
+    // tag_request, with_request_id
+    #[actix_rt::test]
+    async fn request_id_propagated()
+    {
+        use actix_web::{dev::Service, test, App};
+        //what the database layer would log as the purpose of a query
+        async fn purpose() -> HttpResponse {HttpResponse::Ok().body(sql::tag_purpose("testing"))}
+        let mut app = test::init_service(App::new()
+            .wrap_fn(|mut req, srv| {
+                let id = tag_request(req.headers_mut(), true);
+                with_request_id(srv.call(req), id)
+            })
+            .route("/", web::get().to(purpose))
+        ).await;
+
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/").to_request()).await;
+        let id = resp.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string();
+        assert_eq!(id.len(), 36);
+        assert_eq!(test::read_body(resp).await, format!("testing (request {})", id).into_bytes());
+
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/").header("X-Request-Id", "lb-4f2a").to_request()).await;
+        assert_eq!(resp.headers().get(REQUEST_ID_HEADER).unwrap(), "lb-4f2a");
+        assert_eq!(test::read_body(resp).await, &b"testing (request lb-4f2a)"[..]);
+
+        //not safe to log, so replaced
+        let resp = test::call_service(&mut app, test::TestRequest::get().uri("/").header("X-Request-Id", "a b\"c").to_request()).await;
+        assert_ne!(resp.headers().get(REQUEST_ID_HEADER).unwrap(), "a b\"c");
+
+        //outside a request
+        assert_eq!(sql::tag_purpose("testing"), "testing");
+    }
+
+    // maintenance_response
+    #[test]
+    fn maintenance_mode()
+    {
+        let flag = AtomicBool::new(false);
+        assert!(maintenance_response("/api/prices/1586300000/1586400000", &flag).is_none());
+
+        flag.store(true, Ordering::Relaxed);
+        let resp = maintenance_response("/api/prices/1586300000/1586400000", &flag).unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+        assert!(maintenance_response("/api/recent/5", &flag).is_some());
+        //still answering, so maintenance can be checked on and turned off
+        assert!(maintenance_response("/metrics", &flag).is_none());
+        assert!(maintenance_response("/admin/maintenance", &flag).is_none());
+        assert!(maintenance_response("/", &flag).is_none());
+
+        flag.store(false, Ordering::Relaxed);
+        assert!(maintenance_response("/api/prices/1586300000/1586400000", &flag).is_none());
+    }
+
+    // empty_range_status
+    #[test]
+    fn empty_ranges()
+    {
+        let nothing: Vec<(u64,u64,bool)> = vec![];
+        let only_virtual = vec![(0, 439, true)];
+        let real = vec![(0, 439, true), (1325347200, 441, false)];
+
+        assert_eq!(empty_range_status(&nothing, EmptyRange::Ok), None);
+        assert_eq!(empty_range_status(&only_virtual, EmptyRange::Ok), None);
+
+        assert_eq!(empty_range_status(&nothing, EmptyRange::NoContent), Some(StatusCode::NO_CONTENT));
+        assert_eq!(empty_range_status(&only_virtual, EmptyRange::NoContent), Some(StatusCode::NO_CONTENT));
+        assert_eq!(empty_range_status(&real, EmptyRange::NoContent), None);
+    }
+
+    // db_permit
+    #[test]
+    fn db_permits_saturate()
+    {
+        let permits = Semaphore::new(2);
+        let first = db_permit(&permits).unwrap();
+        let _second = db_permit(&permits).unwrap();
+
+        let rejected = db_permit(&permits).unwrap_err();
+        assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(rejected.headers().get(header::RETRY_AFTER).unwrap(), "1");
+
+        //a finished request frees its permit
+        drop(first);
+        assert!(db_permit(&permits).is_ok());
+    }
+
+    // cents_json
+    #[test]
+    fn scaled_prices()
+    {
+        //cents stay integers
+        assert_eq!(cents_json(892153, 100), json!(892153));
+        //a sub-cent price stored in micros
+        assert_eq!(cents_json(123, 1000000), json!(0.0123));
+        assert_eq!(cents_json(-2500000, 1000000), json!(-250.0));
+    }
+
+    // fill_gaps
+    #[test]
+    fn closed_market_not_filled()
+    {
+        let day = 86400;
+        //Thursday 2020-04-09, then nothing until Tuesday
+        let thursday = 1586390400;
+        let rows = vec![(thursday, 100, false), (thursday + 5*day, 110, false)];
+        let windows = trading_hours::parse_windows(&[String::from("mon-fri 00:00-24:00")]).unwrap();
+
+        let filled = fill_gaps(rows, day, thursday, thursday + 5*day, &windows);
+        let times: Vec<u64> = filled.iter().map(|p| p.0).collect();
+        //Friday and Monday are missing data, the weekend is just closed
+        assert_eq!(times, vec![thursday, thursday + day, thursday + 4*day, thursday + 5*day]);
+        assert_eq!(filled[1].1, None);
+    }
+
+    // raw_page
+    #[test]
+    fn raw_pages()
+    {
+        //a full page and one more: there's another page
+        let page = raw_page(vec![(100, 439), (200, 441), (300, 445)], 2, 4, 100);
+        assert_eq!(page, json!({"data": [[100, 439], [200, 441]], "next_offset": 6}));
+        //the last page
+        let page = raw_page(vec![(100, 439), (200, 441)], 2, 4, 100);
+        assert_eq!(page, json!({"data": [[100, 439], [200, 441]], "next_offset": null}));
+        assert_eq!(raw_page(vec![], 1000, 0, 100), json!({"data": [], "next_offset": null}));
+        //prices stored in finer units are still in cents
+        assert_eq!(raw_page(vec![(100, 4395)], 10, 0, 1000)["data"], json!([[100, 439.5]]));
+    }
+
+    // csv_lines
+    #[test]
+    fn csv_rows()
+    {
+        assert_eq!(csv_lines(vec![(1325346600, json!(439)), (1325350200, json!(441.5))]).concat(), "when,price_cents\n1325346600,439\n1325350200,441.5\n");
+        assert_eq!(csv_lines(vec![]), vec![String::from("when,price_cents\n")]);
+    }
+
+    // accepted_format
+    #[test]
+    fn negotiated_formats()
+    {
+        let format = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_str(accept).unwrap());
+            accepted_format(&headers)
+        };
+        assert_eq!(accepted_format(&HeaderMap::new()), BodyFormat::Json);
+        assert_eq!(format("application/msgpack"), BodyFormat::MessagePack);
+        assert_eq!(format("application/x-msgpack"), BodyFormat::MessagePack);
+        assert_eq!(format("application/cbor"), BodyFormat::Cbor);
+        assert_eq!(format("text/html, */*"), BodyFormat::Json);
+        assert_eq!(format("image/png"), BodyFormat::Json);
+        //quality decides, and a named type beats a wildcard
+        assert_eq!(format("application/json;q=0.5, application/cbor"), BodyFormat::Cbor);
+        assert_eq!(format("application/cbor;q=0.5, application/json"), BodyFormat::Json);
+        assert_eq!(format("*/*, application/msgpack"), BodyFormat::MessagePack);
+        assert_eq!(format("application/msgpack;q=0"), BodyFormat::Json);
+    }
+
+    // encode_body
+    #[test]
+    fn encoded_bodies()
+    {
+        let points = vec![json!([1325346600, 439]), json!({"t": 1325350200, "v": 441.5})];
+        assert_eq!(encode_body(&points, BodyFormat::Json).unwrap(), br#"[[1325346600,439],{"t":1325350200,"v":441.5}]"#.to_vec());
+        let packed = encode_body(&points, BodyFormat::MessagePack).unwrap();
+        assert_eq!(rmp_serde::from_slice::<serde_json::Value>(&packed).unwrap(), json!(points));
+        let cbor = encode_body(&points, BodyFormat::Cbor).unwrap();
+        assert_eq!(serde_cbor::from_slice::<serde_json::Value>(&cbor).unwrap(), json!(points));
+        //both are smaller than the JSON
+        assert!(packed.len() < 40 && cbor.len() < 40);
+    }
+
+    // point_count
+    #[test]
+    fn requested_points()
+    {
+        assert_eq!(point_count(None, 1000).ok(), Some(100));
+        assert_eq!(point_count(Some(500), 1000).ok(), Some(500));
+        assert_eq!(point_count(Some(1000), 1000).ok(), Some(1000));
+        //a lower maximum caps the default too
+        assert_eq!(point_count(None, 20).ok(), Some(20));
+        for &bad in &[0, 1001]
+        {
+            let resp = point_count(Some(bad), 1000).err().unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    // candle_interval
+    #[test]
+    fn candle_intervals()
+    {
+        assert_eq!(candle_interval("1h"), Some((3600, 0)));
+        assert_eq!(candle_interval("4h"), Some((14400, 0)));
+        assert_eq!(candle_interval("1d"), Some((86400, 0)));
+        //the origin is a Monday
+        let (week, monday) = candle_interval("1w").unwrap();
+        assert_eq!(week, 604800);
+        assert_eq!(chrono::Datelike::weekday(&chrono::NaiveDateTime::from_timestamp(monday as i64, 0)), chrono::Weekday::Mon);
+        assert_eq!(candle_interval("1m"), None);
+        assert_eq!(candle_interval("1H"), None);
+    }
+
+    // field_names, price_points_json
+    #[test]
+    fn renamed_fields()
+    {
+        let rows = vec![(0, Some(439), true), (1325347200, Some(441), false)];
+
+        let short = price_points_json(rows.clone(), false, false, field_names(Some("short")).unwrap());
+        assert_eq!(json!(short), json!([{"t": 0, "v": 439}, {"t": 1325347200, "v": 441}]));
+
+        let marked = price_points_json(rows.clone(), true, false, field_names(Some("short")).unwrap());
+        assert_eq!(json!(marked), json!([{"t": 0, "v": 439, "s": true}, {"t": 1325347200, "v": 441, "s": false}]));
+
+        let long = price_points_json(rows, false, false, field_names(Some("long")).unwrap());
+        assert_eq!(long[1], json!({"when": 1325347200, "avg_price_cents": 441}));
+
+        assert_eq!(field_names(None), Some(None));
+        assert_eq!(field_names(Some("medium")), None);
+    }
+
+    // hour_of_day_buckets
+    #[test]
+    fn hour_buckets()
+    {
+        //two days of prices seeded at 00:00, 01:00 and 13:00 UTC, averaged per hour by the query
+        let rows = vec![(0, 1000, 2), (1, 1050, 2), (13, 1200, 2)];
+        let buckets = hour_of_day_buckets(rows, 100);
+
+        assert_eq!(buckets.len(), 24);
+        assert_eq!(buckets[0], json!([0, 1000, 2]));
+        assert_eq!(buckets[1], json!([1, 1050, 2]));
+        assert_eq!(buckets[2], json!([2, null, 0]));
+        assert_eq!(buckets[13], json!([13, 1200, 2]));
+        assert_eq!(buckets[23], json!([23, null, 0]));
+    }
+
+    // ndjson_lines
+    #[test]
+    fn ndjson()
+    {
+        let rows = vec![(1325347200, Some(441), false), (1325433600, Some(460), false), (1325520000, Some(455), false)];
+        let body: String = ndjson_lines(price_points_json(rows, false, false, None)).concat();
+
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for line in lines
+        {
+            let point: (u64, u32) = serde_json::from_str(line).unwrap();
+            assert!(point.0 >= 1325347200);
+        }
+        assert!(body.ends_with('\n'));
+    }
+
+    // client_ip
+    #[test]
+    fn forwarded_client_ip()
+    {
+        use actix_web::test::TestRequest;
+        let proxy: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+
+        let forwarded = TestRequest::default()
+            .peer_addr(proxy)
+            .header("X-Forwarded-For", "10.0.0.1, 203.0.113.7")
+            .header("X-Real-IP", "198.51.100.4")
+            .to_http_request();
+        assert_eq!(client_ip(forwarded.headers(), forwarded.peer_addr(), true), Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(client_ip(forwarded.headers(), forwarded.peer_addr(), false), Some(proxy.ip()));
+
+        let real_ip = TestRequest::default().peer_addr(proxy).header("X-Real-IP", "198.51.100.4").to_http_request();
+        assert_eq!(client_ip(real_ip.headers(), real_ip.peer_addr(), true), Some("198.51.100.4".parse().unwrap()));
+
+        let garbage = TestRequest::default().peer_addr(proxy).header("X-Forwarded-For", "unknown").to_http_request();
+        assert_eq!(client_ip(garbage.headers(), garbage.peer_addr(), true), Some(proxy.ip()));
+    }
+}
@@ -0,0 +1,119 @@
+use actix_web::{web, HttpResponse, http::header, http::StatusCode};
+use actix_http::ResponseBuilder;
+
+use crate::sql;
+use super::{check_range, db_permit, json_response, segment_size, stored_series, DB_PERMITS, DEFAULT_SEGMENTS};
+
+/**
+Optional query string parameters accepted by the sparkline endpoint
+*/
+#[derive(Deserialize)]
+pub struct SparklineOptions
+{
+    /// Width of the image in pixels, clamped to between 10 and 1000
+    #[serde(default = "default_width")]
+    pub width: u32,
+    /// Height of the image in pixels, clamped to between 10 and 1000
+    #[serde(default = "default_height")]
+    pub height: u32
+}
+
+fn default_width() -> u32 {100}
+fn default_height() -> u32 {30}
+
+/**
+Responds to requests for the sparkline endpoint, a tiny line chart of a range for places that can't run the frontend's javascript.
+
+# Parameters
+- `range`: actix-generated tuple containing the captured parameters "begin" and "end"
+- `options`: actix-generated struct containing the optional query string parameters
+
+# Returns
+HttpResponse containing (if successful) an SVG image.
+
+# Errors
+The HttpResponse can also indicate failure, which happens when anything goes wrong like
+invalid input or a database error. In this case the body will be JSON containing only a string describing the error.
+*/
+pub async fn sparkline(range: web::Path<(u64, u64)>, options: web::Query<SparklineOptions>) -> HttpResponse
+{
+    let (begin, end) = match check_range(&range) {Ok(r) => r, Err(resp) => {return resp;}};
+    let width = options.width.clamp(10, 1000);
+    let height = options.height.clamp(10, 1000);
+
+    let _permit = match db_permit(&DB_PERMITS) {Ok(p) => p, Err(resp) => {return resp;}};
+    match sql::price_range(begin, end, segment_size(begin, end, DEFAULT_SEGMENTS), false).await
+    {
+        Err(e) => json_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}",e)),
+        Ok(rows) => {
+            //the virtual points at either end of time would squash the stored ones into a corner
+            let series = stored_series(rows);
+            ResponseBuilder::new(StatusCode::OK)
+                .set_header(header::CONTENT_TYPE, "image/svg+xml")
+                .body(sparkline_svg(&series, width, height))
+        }
+    }
+}
+
+/**
+Draws a series as a polyline filling an SVG image, with time across and price up.
+
+# Parameters
+- `series`: (when, price) points in time order
+- `width`, `height`: Size of the image
+
+# Returns
+The SVG document. A series with no change in price is drawn as a flat line through the middle.
+*/
+fn sparkline_svg(series: &[(u64, u64)], width: u32, height: u32) -> String
+{
+    let (w, h) = (width as f64, height as f64);
+    let first_t = series.first().map_or(0, |p| p.0);
+    let last_t = series.last().map_or(0, |p| p.0);
+    let min_p = series.iter().map(|p| p.1).min().unwrap_or(0);
+    let max_p = series.iter().map(|p| p.1).max().unwrap_or(0);
+
+    let points: Vec<String> = series.iter().map(|&(when, price)| {
+        let x = if last_t > first_t {(when - first_t) as f64 / (last_t - first_t) as f64 * w} else {0.0};
+        let y = if max_p > min_p {h - (price - min_p) as f64 / (max_p - min_p) as f64 * h} else {h / 2.0};
+        format!("{:.2},{:.2}", x, y)
+    }).collect();
+
+    format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\"><polyline fill=\"none\" stroke=\"currentColor\" stroke-width=\"1\" points=\"{p}\"/></svg>",
+        w=width, h=height, p=points.join(" "))
+}
+
+/*
+Test those functions which weren't able to have good tests as part of their
+example usage in the docs, but are still possible to unit-test
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // sparkline_svg
+    #[test]
+    fn sparkline_points()
+    {
+        let series = vec![(0, 400), (100, 600), (200, 500), (300, 800), (400, 400)];
+        let svg = sparkline_svg(&series, 100, 40);
+
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"100\" height=\"40\" viewBox=\"0 0 100 40\">"));
+        let points = svg.split("points=\"").nth(1).unwrap().split('"').next().unwrap();
+        let points: Vec<&str> = points.split(' ').collect();
+        assert_eq!(points.len(), series.len());
+        //lowest price at the bottom left, highest at the top
+        assert_eq!(points[0], "0.00,40.00");
+        assert_eq!(points[3], "75.00,0.00");
+        assert_eq!(points[4], "100.00,40.00");
+
+        //flat and empty series
+        assert!(sparkline_svg(&[(0, 5), (10, 5)], 100, 40).contains("points=\"0.00,20.00 100.00,20.00\""));
+        assert!(sparkline_svg(&[], 100, 40).contains("points=\"\""));
+
+        //only the stored prices are drawn, spread across the whole width
+        let rows = vec![(0, 439, true), (100, 600, false), (200, 500, false), (u64::MAX - 15, 500, true)];
+        assert!(sparkline_svg(&stored_series(rows), 100, 40).contains("points=\"0.00,0.00 100.00,40.00\""));
+    }
+}
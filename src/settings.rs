@@ -1,41 +1,411 @@
-use clap::{Arg, App};
-use config::{ConfigError, Config, File};
-use log::{error/*, warn, info, debug, trace, log, Level*/};
+use clap::{value_t, Arg, App, SubCommand};
+use config::{ConfigError, Config, File, FileFormat};
+use log::{error, warn, info, /*debug, trace, log, Level*/};
 use std::env;
 use std::fs;
+use std::io;
 use std::path::Path;
+use std::sync::Mutex;
+
+use crate::trading_hours;
+
+const PATH_LOG4RS_CONFIG: &str = "config/log4rs.yml";
+
+/// The pair the `[source]` exchanges quote, and the one served by the endpoints that don't name a pair
+pub const DEFAULT_PAIR: &str = "btcusd";
 
 /**
 The portion of the config needed immediately, before we can even do so much as display an error over HTTP.
 */
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Startup
 {
     pub working_dir: String,
-    pub listen_addr: String
+    pub listen_addr: String,
+    /// Subfolders of the working dir to create at startup if they're missing. Only the command line or environment can set this, since the config file lives in one of them.
+    pub create_dirs: Vec<String>
 }
 
 /**
 The portion of the config needed for mysql database connections.
 */
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Mysql
 {
     pub host: String,
     pub port: u16,
     pub user: String,
     pub password: String,
-    pub db: String
+    pub db: String,
+    /// Host of a read replica for the HTTP handlers' queries, leaving the primary to the updater. Empty uses the primary for everything.
+    pub read_host: String,
+    /// Port of the read replica. 0 uses `port`.
+    pub read_port: u16,
+    /// Check that the database has the expected table and columns when the connection pool is created, failing fast if not.
+    /// Leave this off for the first run, which creates the table after connecting.
+    pub validate_schema_on_connect: bool,
+    /// Tell MySQL to use the primary key for the range query's lookups with `FORCE INDEX (PRIMARY)`,
+    /// for when its optimizer picks a full scan on a large table.
+    pub force_primary_index: bool
+}
+
+/**
+Where the virtual data point at the beginning of time, used to pad ranges that start before the data, gets its price.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SyntheticFirst
+{
+    /// Use the price of the earliest stored row
+    EarliestRow,
+    /// Use the configured `synthetic_first_cents`
+    Constant
+}
+
+/**
+How the prices endpoint responds to a range without any stored data in it.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyRange
+{
+    /// 200 OK with whatever points there are, which may be none or only virtual ones
+    Ok,
+    /// 204 No Content
+    NoContent
+}
+
+/**
+What the generated pages add to the URLs of local static files, so browsers fetch them again after they change.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetVersion
+{
+    /// The file's modification time, read when the first page is generated
+    Mtime,
+    /// The version of the app
+    CrateVersion,
+    /// Nothing
+    Off
+}
+
+/**
+The portion of the config controlling how HTTP responses are served.
+*/
+#[derive(Deserialize, Serialize)]
+pub struct Http
+{
+    /// Ranges ending less than this many seconds ago are considered to touch the present, and may still change
+    pub recent_window_secs: u64,
+    /// Cache-Control max-age for price data in ranges touching the present
+    pub recent_max_age_secs: u64,
+    /// Cache-Control max-age for price data in ranges that ended before the recent window
+    pub historical_max_age_secs: u64,
+    /// Where the price of the virtual point at the beginning of time comes from
+    pub synthetic_first: SyntheticFirst,
+    /// Price of the virtual point at the beginning of time when `synthetic_first` is "constant"
+    pub synthetic_first_cents: u32,
+    /// Take the client IP from the X-Forwarded-For/X-Real-IP headers, using the last address in X-Forwarded-For. Only enable this behind a reverse proxy that sets them, since clients can forge them.
+    pub trusted_proxy: bool,
+    /// Keep the X-Request-Id a client or proxy sends as the ID of its request, instead of always making a new one
+    pub accept_request_id: bool,
+    /// How the prices endpoint responds to a range without any stored data in it
+    pub empty_range: EmptyRange,
+    /// Most requests that may be querying the database at once. Requests beyond this get 503 Service Unavailable rather than waiting.
+    pub max_concurrent_queries: usize,
+    /// Most clients that may be connected to the live updates WebSocket at once. Any more are closed right away, told to try again later.
+    pub max_subscribers: usize,
+    /// Milliseconds a request may take before it's answered with 503 Service Unavailable instead. 0 lets requests take as long as they need.
+    /// Database queries run on the blocking thread pool, so one that is cut off finishes there and its result is thrown away.
+    pub request_timeout_ms: u64,
+    /// Seconds to let open connections, and the updater's current work, finish when shutting down before they're cut off
+    pub shutdown_timeout_secs: u64,
+    /// How hard to gzip responses for clients that accept it, from 1 (fastest) to 9 (smallest). 0 turns compression off.
+    pub gzip_level: u32,
+    /// What to add to the URLs of local static files in generated pages as `?v=`, so a deploy doesn't leave browsers with stale copies
+    pub asset_version: AssetVersion,
+    /// Show a "collecting data" notice on the main page instead of the chart while no prices are stored yet
+    pub empty_notice: bool,
+    /// Fewest prices that must be stored before the prices endpoint serves data. Until then it answers 503 Service Unavailable saying it's warming up. 0 always serves.
+    pub min_points: u64,
+    /// Put the prices for the chart's starting range in the main page, so it can draw them without waiting on a request to the prices endpoint
+    pub embed_initial_data: bool,
+    /// Most points a client may ask the prices endpoint for with `points`
+    pub max_points: u64
+}
+
+/**
+What to do when storing a price for a time that already has one.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OnDuplicate
+{
+    /// Keep the price already stored
+    Ignore,
+    /// Replace it with the new price
+    Update
+}
+
+/**
+A column of `price_history` that the database computes from `when` as each row is stored, for quick grouping.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DerivedColumn
+{
+    /// `day_of_week`: 0 for Monday through 6 for Sunday, in UTC
+    DayOfWeek,
+    /// `is_weekend`: 1 on Saturday and Sunday, in UTC
+    IsWeekend
+}
+
+/**
+Where the history table's first prices come from, when it's created.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SeedSource
+{
+    /// Import the history file, history/bitstamp.csv
+    Csv,
+    /// Fetch the last `backfill_days` of hourly prices from the source
+    Backfill,
+    /// Start empty and let the updater fill it in from now on
+    None
+}
+
+/**
+How prices are rounded to whole units of `price_scale` when they're stored.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding
+{
+    /// Drop the fraction, toward zero
+    Trunc,
+    /// To the nearest unit, with halves away from zero
+    Nearest,
+    /// Down
+    Floor,
+    /// Up
+    Ceil
+}
+
+/**
+Which field of the price source's hourly ticker becomes the stored price.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceField
+{
+    /// Volume weighted average price over the hour
+    Vwap,
+    /// Price of the last trade
+    Last,
+    /// Price of the first trade of the hour
+    Open,
+    /// Highest price of the hour
+    High,
+    /// Lowest price of the hour
+    Low
+}
+
+/**
+The portion of the config controlling the background updater that fetches new prices.
+*/
+#[derive(Deserialize, Serialize)]
+pub struct Updater
+{
+    /// Consecutive fetch failures after which the source is skipped for a cooldown. 0 never skips it.
+    pub breaker_failure_threshold: u32,
+    /// Seconds to skip a failing source before making a single trial request
+    pub breaker_cooldown_secs: u64,
+    /// Seconds to wait for a source to answer before giving up on it and trying the next. 0 waits as long as it takes.
+    pub fetch_timeout_secs: u64,
+    /// Consecutive failures to store a price after which the updater stops fetching for a cooldown, retrying only the prices it already has. 0 never stops.
+    pub write_failure_threshold: u32,
+    /// Seconds to stop fetching while the database can't be written to, unless a retried price is stored first
+    pub write_cooldown_secs: u64,
+    /// What to do when the import or the updater stores a price for a time that already has one
+    pub on_duplicate: OnDuplicate,
+    /// Most fetched prices to hold for retrying while the database can't be reached. The oldest are dropped beyond this.
+    pub retry_queue_size: u32,
+    /// Take a database lock before updating, so when several copies of the app share a database only one of them fetches and stores prices.
+    /// The others stand by and keep trying for the lock, taking over if the one holding it stops. The lock is checked before each fetch, and taken again if it was lost with its connection.
+    pub exclusive_updater: bool,
+    /// How many of the most recent prices to keep in memory, so the recent endpoint can answer without querying the database
+    pub recent_buffer_size: u32,
+    /// How many times to restart the updater if it stops, e.g. after an error it can't recover from. 0 never restarts it.
+    pub max_restarts: u32,
+    /// Seconds to wait before the first restart of the updater, doubling for each one after
+    pub restart_backoff_secs: u64,
+    /// Seconds to wait after startup before the updater's first fetch. 0 fetches right away.
+    pub initial_delay_secs: u64,
+    /// Most seconds of random delay added to `initial_delay_secs`, so instances started together don't all call the source at once
+    pub initial_jitter_secs: u64,
+    /// Seconds past each hour to fetch the price, e.g. 300 to always fetch at :05
+    pub fetch_offset_secs: u64,
+    /// Seconds between updates of the source's price, e.g. 3600 for Bitstamp's hourly ticker. The updater doesn't fetch again until
+    /// this long after the latest stored price, since the source would only repeat it. 0 fetches every hour regardless.
+    pub source_update_secs: u64,
+    /// Seconds without storing a price after which the freshness endpoint reports the data as stale
+    pub stale_after_secs: u64,
+    /// Seconds the app's clock may differ from the database's before the updater warns about it, checked each time it runs. 0 skips the check.
+    pub max_clock_skew_secs: u64,
+    /// Units per dollar that prices are stored in: 100 stores cents, 1000000 stores micros. API output is in cents either way.
+    /// Pick it before importing, since stored prices aren't converted when it changes.
+    pub price_scale: u64,
+    /// How prices from the source and the history file are rounded to whole units of `price_scale`
+    pub rounding: Rounding,
+    /// Which field of the source's hourly ticker is stored as the price: "vwap", "last", "open", "high" or "low"
+    pub price_field: PriceField,
+    /// When true, a response from the source with fields the updater doesn't know about is rejected instead of used.
+    /// Meant for debugging a source whose payloads look wrong: the source adding a field also makes every fetch fail.
+    pub strict_source_json: bool,
+    /// When the source's market is open, in UTC, like "mon-fri 14:30-21:00". Empty means always open.
+    /// Outside these hours the updater doesn't fetch, and filled gaps aren't shown as missing data.
+    pub trading_hours: Vec<String>,
+    /// Extra columns the database computes for each stored price, like ["day_of_week", "is_weekend"]. They're added at startup if missing,
+    /// and filled in for the rows already stored. Taking one out of the list leaves the column in place.
+    pub derived_columns: Vec<DerivedColumn>,
+    /// Where the first prices come from when the history table is created: "csv", "backfill" or "none"
+    pub seed: SeedSource,
+    /// Days of hourly prices to fetch from the source when `seed` is "backfill"
+    pub backfill_days: u32
+}
+
+/**
+An exchange the updater can get the latest price from.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Exchange
+{
+    /// Bitstamp's hourly ticker
+    Bitstamp,
+    /// Coinbase's ticker for BTC-USD
+    Coinbase,
+    /// Kraken's ticker for XBTUSD
+    Kraken,
+    /// CoinGecko's aggregated price for bitcoin in USD
+    Coingecko
+}
+
+/**
+The portion of the config choosing where the updater gets prices from.
+*/
+#[derive(Deserialize, Serialize)]
+pub struct Source
+{
+    /// Exchanges the updater gets the latest price from, in order: "bitstamp", "coinbase", "kraken" or "coingecko".
+    /// Each is only tried when the ones before it fail, and has its own circuit breaker.
+    /// `price_field` and `strict_source_json` only apply to Bitstamp. Seeding with `backfill` and the audit endpoint always use Bitstamp.
+    pub exchanges: Vec<Exchange>
+}
+
+/**
+The portion of the config choosing which currency pairs are tracked.
+*/
+#[derive(Deserialize, Serialize)]
+pub struct Pairs
+{
+    /// Pairs the updater polls each hour, as Bitstamp names them, like ["btcusd", "ethusd", "ltcusd"].
+    /// "btcusd" comes from the `[source]` exchanges, and the others from Bitstamp. Each is charted at /api/prices/{pair}/{begin}/{end}.
+    pub symbols: Vec<String>
+}
+
+/**
+The portion of the config controlling the admin endpoints.
+*/
+#[derive(Deserialize, Serialize)]
+pub struct Admin
+{
+    /// Key clients must send in the X-Api-Key header to use the admin endpoints. Empty disables them.
+    pub api_key: String,
+    /// Log file served by the logs endpoint, relative to the working directory
+    pub log_path: String,
+    /// Most lines the logs endpoint will return
+    pub max_log_lines: usize,
+    /// Fewest seconds between runs of the audit endpoint, which makes requests to the price source
+    pub audit_interval_secs: u64
+}
+
+/**
+How the query subcommand prints its results.
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutputFormat
+{
+    /// A `when,price_cents` header, then one line per point
+    Csv,
+    /// A JSON array of `[when, price_cents]` arrays, like the prices endpoint
+    Json
+}
+
+/**
+Something to do instead of running the server, given as a subcommand on the command line.
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Command
+{
+    /// Print the prices for a range of time, resampled into `segments` segments
+    Query{begin: u64, end: u64, segments: u64, format: OutputFormat}
+}
+
+/**
+The subcommand given on the command line, if any. Loads the settings first if they haven't been already, since that's where the command line is read.
+
+# Returns
+The command, the first time this is called. None after that, or when no subcommand was given.
+*/
+pub fn take_command() -> Option<Command>
+{
+    lazy_static::initialize(&SETTINGS);
+    COMMAND.lock().ok().and_then(|mut c| c.take())
+}
+
+/**
+A setting that can't be used, as found by `Settings::validate`.
+*/
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FieldError
+{
+    /// Where the setting is, like "http.gzip_level"
+    pub field: String,
+    /// What is wrong with it
+    pub error: String
+}
+
+/**
+Whether a pair's symbol is one the updater and the API can use: lowercase letters and digits, at most 16 of them.
+
+# Examples
+```
+use bitcoin_trend::settings;
+assert!(settings::is_pair_symbol("ethusd"));
+assert!(!settings::is_pair_symbol("ETH/USD"));
+```
+*/
+pub fn is_pair_symbol(symbol: &str) -> bool
+{
+    !symbol.is_empty() && symbol.len() <= 16 && symbol.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
 }
 
 /**
 The main type storing all the configuration data.
 */
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Settings
 {
     pub startup: Startup,
-    pub mysql: Mysql
+    pub mysql: Mysql,
+    pub http: Http,
+    pub updater: Updater,
+    pub source: Source,
+    pub pairs: Pairs,
+    pub admin: Admin
 }
 
 impl Settings
@@ -49,14 +419,78 @@ impl Settings
     let def_settings: Settings = Settings{
         startup: Startup{
             working_dir: String::from("data"),
-            listen_addr: String::from("0.0.0.0:80")
+            listen_addr: String::from("0.0.0.0:80"),
+            create_dirs: vec![String::from("config"), String::from("log")]
         },
         mysql: Mysql{
             host: String::from("db_host"),
             port: 3306,
             user: String::from("root"),
             password: String::from("passw0rd"),
-            db: String::from("database_1")
+            db: String::from("database_1"),
+            read_host: String::new(),
+            read_port: 0,
+            validate_schema_on_connect: false,
+            force_primary_index: false
+        },
+        http: Http{
+            recent_window_secs: 7200,
+            recent_max_age_secs: 60,
+            historical_max_age_secs: 86400,
+            synthetic_first: SyntheticFirst::Constant,
+            synthetic_first_cents: 439,
+            trusted_proxy: false,
+            accept_request_id: true,
+            empty_range: EmptyRange::Ok,
+            max_concurrent_queries: 32,
+            max_subscribers: 500,
+            request_timeout_ms: 30000,
+            shutdown_timeout_secs: 30,
+            gzip_level: 6,
+            asset_version: AssetVersion::Mtime,
+            empty_notice: true,
+            min_points: 0,
+            embed_initial_data: false,
+            max_points: 1000
+        },
+        updater: Updater{
+            breaker_failure_threshold: 5,
+            breaker_cooldown_secs: 10800,
+            fetch_timeout_secs: 30,
+            write_failure_threshold: 3,
+            write_cooldown_secs: 10800,
+            on_duplicate: OnDuplicate::Ignore,
+            retry_queue_size: 168,
+            exclusive_updater: false,
+            recent_buffer_size: 168,
+            max_restarts: 10,
+            restart_backoff_secs: 60,
+            initial_delay_secs: 0,
+            initial_jitter_secs: 0,
+            fetch_offset_secs: 300,
+            source_update_secs: 3600,
+            stale_after_secs: 7200,
+            max_clock_skew_secs: 60,
+            price_scale: 100,
+            rounding: Rounding::Nearest,
+            price_field: PriceField::Vwap,
+            strict_source_json: false,
+            trading_hours: vec![],
+            derived_columns: vec![],
+            seed: SeedSource::Csv,
+            backfill_days: 30
+        },
+        source: Source{
+            exchanges: vec![Exchange::Bitstamp, Exchange::Coinbase, Exchange::Coingecko]
+        },
+        pairs: Pairs{
+            symbols: vec![String::from("btcusd")]
+        },
+        admin: Admin{
+            api_key: String::new(),
+            log_path: String::from("log/main.log"),
+            max_log_lines: 1000,
+            audit_interval_secs: 600
         }
     };
 
@@ -67,8 +501,113 @@ impl Settings
     */
     pub fn to_toml(&self) -> String
     {
-        format!("[startup]\nworking_dir = \"{}\"\nlisten_addr = \"{}\"\n[mysql]\nhost = \"{}\"\nport = {}\nuser = \"{}\"\npassword = \"{}\"\ndb = \"{}\"\n",
-            self.startup.working_dir, self.startup.listen_addr, self.mysql.host, self.mysql.port, self.mysql.user, self.mysql.password, self.mysql.db)
+        toml::to_string(self).expect("Couldn't serialize settings")
+    }
+
+    /**
+    Parse a config file the way it is loaded at startup, filling in defaults for anything it leaves out,
+    without applying it or touching the command line.
+
+    # Returns
+    The settings, or a String describing why the file couldn't be parsed into them.
+
+    # Examples
+    ```
+    use bitcoin_trend::settings::Settings;
+    let settings = Settings::from_toml("[http]\ngzip_level = 9").unwrap();
+    assert_eq!(settings.http.gzip_level, 9);
+    assert!(Settings::from_toml("[http]\ngzip_level = \"high\"").is_err());
+    ```
+    */
+    pub fn from_toml(text: &str) -> Result<Settings, String>
+    {
+        let mut file_config = Config::new();
+        file_config.merge(File::from_str(&DEFAULT_SETTINGS.to_toml(), FileFormat::Toml)).map_err(|e| format!("Couldn't load default settings: {}", e))?;
+        file_config.merge(File::from_str(text, FileFormat::Toml)).map_err(|e| format!("Couldn't parse config: {}", e))?;
+        let mut settings: Settings = file_config.try_into().map_err(|e| format!("Couldn't export config: {}", e))?;
+        settings.trim_strings();
+        Ok(settings)
+    }
+
+    /**
+    Trims whitespace from the ends of the settings that name things, like hosts and paths, where it's never meant to be there
+    and only causes confusing failures, e.g. from a value pasted with a trailing space.
+    The password and API key are left alone, since whitespace could be part of them.
+
+    # Returns
+    The names of the settings that were trimmed.
+
+    # Examples
+    ```
+    use bitcoin_trend::settings::Settings;
+    let mut settings = Settings::from_toml("").unwrap();
+    settings.mysql.host = String::from("db ");
+    assert_eq!(settings.trim_strings(), vec!["mysql.host"]);
+    assert_eq!(settings.mysql.host, "db");
+    ```
+    */
+    pub fn trim_strings(&mut self) -> Vec<&'static str>
+    {
+        let fields: [(&'static str, &mut String); 7] = [
+            ("startup.working_dir", &mut self.startup.working_dir),
+            ("startup.listen_addr", &mut self.startup.listen_addr),
+            ("mysql.host", &mut self.mysql.host),
+            ("mysql.user", &mut self.mysql.user),
+            ("mysql.db", &mut self.mysql.db),
+            ("mysql.read_host", &mut self.mysql.read_host),
+            ("admin.log_path", &mut self.admin.log_path)
+        ];
+        let mut trimmed = Vec::new();
+        for (name, value) in fields
+        {
+            if value.trim().len() != value.len()
+            {
+                *value = value.trim().to_string();
+                trimmed.push(name);
+            }
+        }
+        trimmed
+    }
+
+    /**
+    Check the settings for values that parse but can't work.
+
+    # Returns
+    One error per bad setting, empty when they're all usable.
+
+    # Examples
+    ```
+    use bitcoin_trend::settings::Settings;
+    let mut settings = Settings::from_toml("").unwrap();
+    assert!(settings.validate().is_empty());
+    settings.startup.listen_addr = String::from("0.0.0.0");
+    assert_eq!(settings.validate()[0].field, "startup.listen_addr");
+    ```
+    */
+    pub fn validate(&self) -> Vec<FieldError>
+    {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, field: &str, error: &str| if !ok {errors.push(FieldError{field: String::from(field), error: String::from(error)});};
+
+        let listen_port = self.startup.listen_addr.rsplit_once(':').map(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        check(listen_port == Some(true), "startup.listen_addr", "must be a host and port, like 0.0.0.0:80");
+        check(!self.mysql.host.is_empty(), "mysql.host", "must not be empty");
+        check(self.mysql.port != 0, "mysql.port", "must be between 1 and 65535");
+        check(self.http.max_concurrent_queries > 0, "http.max_concurrent_queries", "must be at least 1, or every request querying the database is refused");
+        check(self.http.max_subscribers > 0, "http.max_subscribers", "must be at least 1, or every live updates client is refused");
+        check(self.http.max_points > 0, "http.max_points", "must be at least 1");
+        check(self.http.gzip_level <= 9, "http.gzip_level", "must be between 0 and 9");
+        check(self.updater.price_scale > 0, "updater.price_scale", "must be at least 1");
+        check(self.updater.fetch_offset_secs < 60*60, "updater.fetch_offset_secs", "must be less than an hour");
+        check(!self.source.exchanges.is_empty(), "source.exchanges", "must list at least one exchange");
+        check(!self.pairs.symbols.is_empty(), "pairs.symbols", "must list at least one pair");
+        check(self.pairs.symbols.iter().all(|p| is_pair_symbol(p)), "pairs.symbols", "must be lowercase letters and digits, at most 16 of them, like \"ethusd\"");
+        check(self.pairs.symbols.iter().enumerate().all(|(i, p)| !self.pairs.symbols[..i].contains(p)), "pairs.symbols", "must not list a pair twice");
+        if let Err(e) = trading_hours::parse_windows(&self.updater.trading_hours)
+        {
+            check(false, "updater.trading_hours", &e);
+        }
+        errors
     }
 
     /**
@@ -77,13 +616,14 @@ impl Settings
     - Load app & logger config, merging values from all sources (cmd, env, file, defaults) with appropriate priority
     - Store app config in a lazy_static ref settings::SETTINGS
     - Set the working directory of the app to what is configured, so relative paths work correctly.
+    - Create any of the configured subfolders of the working directory that are missing.
     - If either config file is missing, write a new one with default settings.
     - Start up logger.
 
     # Panics
     This function makes every attempt to recover from minor issues, but any unrecoverable problem will result in a panic.
     After all, the app can't safely do much of anything without the info it returns, and even the logger isn't available until the very end.
-    Possible unrecoverables include CWD change error, filesystem errors, and config parse errors.
+    Possible unrecoverables include CWD change error, filesystem errors, config parse errors, and settings that fail `validate`.
 
     # Undefined behavior
     This should only be called once. Additional calls may result in issues with the underlying config and logger libraries.
@@ -92,8 +632,8 @@ impl Settings
     fn new() -> Self
     {
         let path_config = "config/config.toml";
-        let path_log4rs_config = "config/log4rs.yml";
         let mysql_default_port_str = format!("{}",DEFAULT_SETTINGS.mysql.port);
+        let create_dirs_default_str = DEFAULT_SETTINGS.startup.create_dirs.join(",");
         //std::env::set_var("RUST_LOG", "my_errors=debug,actix_web=info");
         //std::env::set_var("RUST_BACKTRACE", "1");
         
@@ -115,6 +655,14 @@ impl Settings
                 .help("ip:port to listen on. Use 0.0.0.0 for the ip to listen on all interfaces.")
                 .default_value(&DEFAULT_SETTINGS.startup.listen_addr)
                 .takes_value(true))
+            .arg(Arg::with_name("create_dirs")
+                .short("c")
+                .long("create-dirs")
+                .env("BITCOIN_TREND_CREATE_DIRS")
+                .help("Comma separated list of subfolders of the working directory to create if they don't exist.")
+                .default_value(&create_dirs_default_str)
+                .use_delimiter(true)
+                .takes_value(true))
             .arg(Arg::with_name("mysql_host")
                 .short("h")
                 .long("mysql-host")
@@ -150,15 +698,52 @@ impl Settings
                 .help("Database name for the mysql connection")
                 .default_value(&DEFAULT_SETTINGS.mysql.db)
                 .takes_value(true))
+            .subcommand(SubCommand::with_name("query")
+                .about("Print the prices for a range of time and exit, without starting the server")
+                .arg(Arg::with_name("begin")
+                    .help("Unix timestamp where the range begins")
+                    .required(true))
+                .arg(Arg::with_name("end")
+                    .help("Unix timestamp where the range ends")
+                    .required(true))
+                .arg(Arg::with_name("segments")
+                    .long("segments")
+                    .help("How many segments to resample the range into")
+                    .default_value("100")
+                    .takes_value(true))
+                .arg(Arg::with_name("format")
+                    .long("format")
+                    .help("Output format")
+                    .possible_values(&["csv", "json"])
+                    .default_value("csv")
+                    .takes_value(true)))
             .get_matches();
     
+        //a subcommand replaces running the server, so remember it for main
+        if let Some(sub) = cmd_matches.subcommand_matches("query")
+        {
+            *COMMAND.lock().unwrap() = Some(Command::Query{
+                begin: value_t!(sub, "begin", u64).unwrap_or_else(|e| e.exit()),
+                end: value_t!(sub, "end", u64).unwrap_or_else(|e| e.exit()),
+                segments: value_t!(sub, "segments", u64).unwrap_or_else(|e| e.exit()),
+                format: if sub.value_of("format") == Some("json") {OutputFormat::Json} else {OutputFormat::Csv}
+            });
+        }
+
         //set cwd
         let working_dir = cmd_matches.value_of("working_dir").expect("Couldn't determine target working dir");
         env::set_current_dir(Path::new(working_dir)).expect("Couldn't set cwd");
 
-        //attempt to load config file
+        //create missing subfolders before anything tries to write into them
+        let create_dirs: Vec<&str> = cmd_matches.values_of("create_dirs").map(|v| v.collect()).unwrap_or_default();
+        let created_dirs = create_missing_dirs(Path::new("."), &create_dirs).expect("Couldn't create missing subfolders of working dir");
+
+        //start from the defaults, so settings missing from an older config file still get a value
         let mut file_config = Config::new();
-        if let Err(ce) = file_config.merge(File::with_name(&path_config))
+        file_config.merge(File::from_str(&DEFAULT_SETTINGS.to_toml(), FileFormat::Toml)).expect("Couldn't load default settings");
+
+        //attempt to load config file
+        if let Err(ce) = file_config.merge(File::with_name(path_config))
         {
             match ce //determine reason for failure
             {
@@ -173,7 +758,7 @@ impl Settings
                     if let Err(e) = fs::write(String::from(path_config), DEFAULT_SETTINGS.to_toml()){
                         panic!("Couldn't read main config file or write default main config file: {}", e);
                     }
-                    file_config.merge(File::with_name(&path_config)).expect("Couldn't load newly written default main config file.");
+                    file_config.merge(File::with_name(path_config)).expect("Couldn't load newly written default main config file.");
                 }
             }
         }
@@ -182,6 +767,7 @@ impl Settings
         let set_e = "Couldn't override config setting";
         if cmd_matches.occurrences_of("working_dir"   ) > 0 {file_config.set("startup.working_dir", cmd_matches.value_of("working_dir"   )).expect(set_e);}
         if cmd_matches.occurrences_of("listen_addr"   ) > 0 {file_config.set("startup.listen_addr", cmd_matches.value_of("listen_addr"   )).expect(set_e);}
+        file_config.set("startup.create_dirs", create_dirs.clone()).expect(set_e);
         if cmd_matches.occurrences_of("mysql_host"    ) > 0 {file_config.set("mysql.host",          cmd_matches.value_of("mysql_host"    )).expect(set_e);}
         if cmd_matches.occurrences_of("mysql_port"    ) > 0 {file_config.set("mysql.port",          cmd_matches.value_of("mysql_port"    )).expect(set_e);}
         if cmd_matches.occurrences_of("mysql_user"    ) > 0 {file_config.set("mysql.user",          cmd_matches.value_of("mysql_user"    )).expect(set_e);}
@@ -189,51 +775,237 @@ impl Settings
         if cmd_matches.occurrences_of("mysql_db"      ) > 0 {file_config.set("mysql.db",            cmd_matches.value_of("mysql_db"      )).expect(set_e);}
 
         //attempt to load logging config
-        if let Err(le) = log4rs::init_file(path_log4rs_config, Default::default())
+        let log_config = match load_log_config(PATH_LOG4RS_CONFIG)
         {
-            match le //determine reason for failure
+            Ok(c) => c,
+            //one that's there but won't load is left alone for the operator to fix, rather than replaced with the defaults
+            Err(e) if Path::new(PATH_LOG4RS_CONFIG).exists() => panic!("{}", e),
+            Err(_) =>
             {
-                log4rs::Error::Log4rs(_) =>
-                {
-                    //looks like the file is missing, attempt to write new file with defaults then load it. If this also fails then bail
-                    if let Err(e) = fs::write(String::from(path_log4rs_config), DEFAULT_LOG4RS.to_string()){
-                        panic!("Couldn't read log config file or write default log config file: {}", e);
-                    }
-                    log4rs::init_file(path_log4rs_config, Default::default()).expect("Couldn't load newly written default log config file.");
-                },
-                _ => {panic!("Couldn't parse log config.");}
+                //the file is missing, attempt to write new file with defaults then load it. If this also fails then bail
+                if let Err(e) = fs::write(PATH_LOG4RS_CONFIG, DEFAULT_LOG4RS.to_string()){
+                    panic!("Couldn't read log config file or write default log config file: {}", e);
+                }
+                load_log_config(PATH_LOG4RS_CONFIG).expect("Couldn't load newly written default log config file.")
             }
+        };
+        //keep the handle so the config can be swapped out later
+        let handle = log4rs::init_config(log_config).expect("Couldn't start logger.");
+        *LOG_HANDLE.lock().unwrap() = Some(handle);
+
+        for dir in created_dirs
+        {
+            info!("Created missing folder {} in working dir", dir);
         }
 
         //Export config to Settings struct
-        match file_config.try_into()
+        let mut settings: Settings = match file_config.try_into()
         {
-            Err(_) => {let e = "Couldn't export config."; error!("{}",e); panic!(e);},
+            Err(e) => {let e = format!("Couldn't export config: {}", e); error!("{}",e); panic!("{}", e);},
             Ok(s) => s
+        };
+        for field in settings.trim_strings()
+        {
+            warn!("Trimmed whitespace from the ends of config setting {}", field);
+        }
+
+        let errors = settings.validate();
+        if !errors.is_empty()
+        {
+            let e = format!("Invalid config: {}", errors.iter().map(|fe| format!("{} {}", fe.field, fe.error)).collect::<Vec<String>>().join("; "));
+            error!("{}",e);
+            panic!("{}", e);
+        }
+        settings
+    }
+}
+
+/**
+Read and parse a log4rs config file, without applying it.
+
+# Returns
+- `Ok`: The parsed config
+- `Err`: String describing why the file couldn't be read or parsed
+*/
+fn load_log_config(path: &str) -> Result<log4rs::config::Config, String>
+{
+    log4rs::load_config_file(path, Default::default()).map_err(|e| format!("Couldn't load log config {}: {}", path, e))
+}
+
+/**
+Re-read the log config file and apply it to the running logger, e.g. to change the log level without a restart.
+
+# Returns
+Result indicating whether the new config was applied.
+- `Err`: String describing the problem. The logger keeps its previous config in that case.
+
+# Examples
+```no_run
+use bitcoin_trend::settings;
+settings::reload_log_config().unwrap();
+```
+*/
+pub fn reload_log_config() -> Result<(), String>
+{
+    let config = load_log_config(PATH_LOG4RS_CONFIG)?;
+    match &*LOG_HANDLE.lock().unwrap()
+    {
+        Some(handle) => {handle.set_config(config); Ok(())},
+        None => Err(String::from("Logger hasn't been started"))
+    }
+}
+
+/**
+Wait for SIGUSR1 forever, reloading the log config each time it arrives.
+It is up to the caller to run this in a separate thread, or be blocked indefinitely.
+
+# Examples
+```no_run
+use bitcoin_trend::settings;
+use std::thread;
+thread::spawn(|| { settings::reload_log_config_on_signal(); });
+```
+*/
+#[cfg(unix)]
+pub fn reload_log_config_on_signal()
+{
+    let mut signals = match signal_hook::iterator::Signals::new([signal_hook::consts::SIGUSR1])
+    {
+        Ok(s) => s,
+        Err(e) => {error!("Couldn't listen for SIGUSR1, log config can't be reloaded: {}", e); return;}
+    };
+    for _ in signals.forever()
+    {
+        match reload_log_config()
+        {
+            Ok(()) => {info!("Reloaded log config after SIGUSR1");},
+            Err(e) => {error!("Couldn't reload log config after SIGUSR1: {}", e);}
         }
     }
 }
 
+/**
+Create the given subfolders of a directory, skipping those that already exist.
+
+# Parameters
+- `base`: The directory to create them in
+- `dirs`: Names of the subfolders
+
+# Returns
+Result indicating whether all the folders exist now.
+- `Ok`: Names of the folders that had to be created
+- `Err`: The filesystem error that stopped us
+
+# Examples
+```no_run
+use bitcoin_trend::settings;
+use std::path::Path;
+let created = settings::create_missing_dirs(Path::new("data"), &["config", "log"]).unwrap();
+```
+*/
+pub fn create_missing_dirs(base: &Path, dirs: &[&str]) -> io::Result<Vec<String>>
+{
+    let mut created = Vec::new();
+    for dir in dirs.iter().filter(|d| !d.is_empty())
+    {
+        let path = base.join(dir);
+        if !path.is_dir()
+        {
+            fs::create_dir_all(&path)?;
+            created.push(String::from(*dir));
+        }
+    }
+    Ok(created)
+}
+
 lazy_static!
 {
     pub static ref SETTINGS: Settings = Settings::new();
 
+    static ref LOG_HANDLE: Mutex<Option<log4rs::Handle>> = Mutex::new(None);
+
+    /// The subcommand given on the command line, if any
+    static ref COMMAND: Mutex<Option<Command>> = Mutex::new(None);
+
     static ref DEFAULT_SETTINGS: Settings = Settings{
         startup: Startup{
             working_dir: String::from("data"),
-            listen_addr: String::from("0.0.0.0:80")
+            listen_addr: String::from("0.0.0.0:80"),
+            create_dirs: vec![String::from("config"), String::from("log")]
         },
         mysql: Mysql{
             host: String::from("db"),
             port: 3306,
             user: String::from("root"),
             password: String::from("j23f24hgf359bgfu4gf4o0i34nf0oi4g"),
-            db: String::from("bitcoin_trend")
+            db: String::from("bitcoin_trend"),
+            read_host: String::new(),
+            read_port: 0,
+            validate_schema_on_connect: false,
+            force_primary_index: false
+        },
+        http: Http{
+            recent_window_secs: 60*60*2,
+            recent_max_age_secs: 60,
+            historical_max_age_secs: 60*60*24,
+            synthetic_first: SyntheticFirst::Constant,
+            synthetic_first_cents: 439,
+            trusted_proxy: false,
+            accept_request_id: true,
+            empty_range: EmptyRange::Ok,
+            max_concurrent_queries: 32,
+            max_subscribers: 500,
+            request_timeout_ms: 30000,
+            shutdown_timeout_secs: 30,
+            gzip_level: 6,
+            asset_version: AssetVersion::Mtime,
+            empty_notice: true,
+            min_points: 0,
+            embed_initial_data: false,
+            max_points: 1000
+        },
+        updater: Updater{
+            breaker_failure_threshold: 5,
+            breaker_cooldown_secs: 60*60*3,
+            fetch_timeout_secs: 30,
+            write_failure_threshold: 3,
+            write_cooldown_secs: 60*60*3,
+            on_duplicate: OnDuplicate::Ignore,
+            retry_queue_size: 24*7,
+            exclusive_updater: false,
+            recent_buffer_size: 24*7,
+            max_restarts: 10,
+            restart_backoff_secs: 60,
+            initial_delay_secs: 0,
+            initial_jitter_secs: 0,
+            fetch_offset_secs: 60*5,
+            source_update_secs: 60*60,
+            stale_after_secs: 2*60*60,
+            max_clock_skew_secs: 60,
+            price_scale: 100,
+            rounding: Rounding::Nearest,
+            price_field: PriceField::Vwap,
+            strict_source_json: false,
+            trading_hours: vec![],
+            derived_columns: vec![],
+            seed: SeedSource::Csv,
+            backfill_days: 30
+        },
+        source: Source{
+            exchanges: vec![Exchange::Bitstamp, Exchange::Coinbase, Exchange::Coingecko]
+        },
+        pairs: Pairs{
+            symbols: vec![String::from("btcusd")]
+        },
+        admin: Admin{
+            api_key: String::new(),
+            log_path: String::from("log/main.log"),
+            max_log_lines: 1000,
+            audit_interval_secs: 600
         }
     };
 
-    static ref DEFAULT_LOG4RS: String = String::from("refresh_rate: 60 seconds
-appenders:
+    static ref DEFAULT_LOG4RS: String = String::from("appenders:
   stdout:
     kind: console
     target: stdout
@@ -287,19 +1059,189 @@ mod tests
         let def_settings: Settings = Settings{
             startup: Startup{
                 working_dir: String::from("data"),
-                listen_addr: String::from("0.0.0.0:80")
+                listen_addr: String::from("0.0.0.0:80"),
+                create_dirs: vec![String::from("config"), String::from("log")]
             },
             mysql: Mysql{
                 host: String::from("db_host"),
                 port: 3306,
                 user: String::from("root"),
                 password: String::from("passw0rd"),
-                db: String::from("database_1")
+                db: String::from("database_1"),
+                read_host: String::new(),
+                read_port: 0,
+                validate_schema_on_connect: false,
+                force_primary_index: false
+            },
+            http: Http{
+                recent_window_secs: 7200,
+                recent_max_age_secs: 60,
+                historical_max_age_secs: 86400,
+                synthetic_first: SyntheticFirst::Constant,
+                synthetic_first_cents: 439,
+                trusted_proxy: false,
+                accept_request_id: true,
+                empty_range: EmptyRange::Ok,
+                max_concurrent_queries: 32,
+                max_subscribers: 500,
+                request_timeout_ms: 30000,
+                shutdown_timeout_secs: 30,
+                gzip_level: 6,
+                asset_version: AssetVersion::Mtime,
+                empty_notice: true,
+                min_points: 0,
+                embed_initial_data: false,
+                max_points: 1000
+            },
+            updater: Updater{
+                breaker_failure_threshold: 5,
+                breaker_cooldown_secs: 10800,
+                fetch_timeout_secs: 30,
+                write_failure_threshold: 3,
+                write_cooldown_secs: 10800,
+                on_duplicate: OnDuplicate::Ignore,
+                retry_queue_size: 168,
+                exclusive_updater: false,
+                recent_buffer_size: 168,
+                max_restarts: 10,
+                restart_backoff_secs: 60,
+                initial_delay_secs: 0,
+                initial_jitter_secs: 0,
+                fetch_offset_secs: 300,
+                source_update_secs: 3600,
+                stale_after_secs: 7200,
+                max_clock_skew_secs: 60,
+                price_scale: 100,
+                rounding: Rounding::Nearest,
+                price_field: PriceField::Vwap,
+                strict_source_json: false,
+                trading_hours: vec![],
+                derived_columns: vec![],
+                seed: SeedSource::Csv,
+                backfill_days: 30
+            },
+            source: Source{
+                exchanges: vec![Exchange::Bitstamp, Exchange::Coinbase, Exchange::Coingecko]
+            },
+            pairs: Pairs{
+                symbols: vec![String::from("btcusd")]
+            },
+            admin: Admin{
+                api_key: String::new(),
+                log_path: String::from("log/main.log"),
+                max_log_lines: 1000,
+                audit_interval_secs: 600
             }
         };
 
         let default_config_file_contents = def_settings.to_toml();
 
         assert_eq!(&default_config_file_contents[..30],"[startup]\nworking_dir = \"data\"");
+        assert!(default_config_file_contents.contains("[http]\nrecent_window_secs = 7200\n"));
+    }
+
+    // settings::load_log_config(), as used by settings::reload_log_config()
+    #[test]
+    fn log_config_levels()
+    {
+        let base = env::temp_dir().join(format!("bitcoin_trend_log4rs_test_{}", std::process::id()));
+        fs::create_dir_all(&base).unwrap();
+        let path_info = base.join("info.yml");
+        let path_debug = base.join("debug.yml");
+        let yaml = "appenders:\n  stdout:\n    kind: console\nroot:\n  level: LEVEL\n  appenders:\n    - stdout\n";
+        fs::write(&path_info, yaml.replace("LEVEL", "info")).unwrap();
+        fs::write(&path_debug, yaml.replace("LEVEL", "debug")).unwrap();
+
+        let info = load_log_config(path_info.to_str().unwrap()).unwrap();
+        let debug = load_log_config(path_debug.to_str().unwrap()).unwrap();
+        assert_eq!(info.root().level(), log::LevelFilter::Info);
+        assert_eq!(debug.root().level(), log::LevelFilter::Debug);
+        assert!(load_log_config(base.join("missing.yml").to_str().unwrap()).is_err());
+
+        fs::remove_dir_all(&base).unwrap();
     }
-}
\ No newline at end of file
+
+    // settings::create_missing_dirs()
+    #[test]
+    fn missing_dirs()
+    {
+        let base = env::temp_dir().join(format!("bitcoin_trend_dirs_test_{}", std::process::id()));
+        fs::create_dir_all(&base).unwrap();
+
+        let created = create_missing_dirs(&base, &["config", "history", "static", "log"]).unwrap();
+        assert_eq!(created, vec!["config", "history", "static", "log"]);
+        for dir in &created
+        {
+            assert!(base.join(dir).is_dir());
+        }
+
+        //nothing left to create the second time
+        let created_again = create_missing_dirs(&base, &["config", "log"]).unwrap();
+        assert!(created_again.is_empty());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    // Settings::from_toml, Settings::trim_strings
+    #[test]
+    fn padded_values_trimmed()
+    {
+        let padded = "[startup]\nlisten_addr = \" 127.0.0.1:4000\\t\"\n[mysql]\nhost = \"db.example.com \"\nuser = \"\\nbitcoin\"\ndb = \"bitcoin_trend\"\npassword = \" spaced pass \"\n";
+        let mut settings = Settings::from_toml(padded).unwrap();
+        assert_eq!(settings.startup.listen_addr, "127.0.0.1:4000");
+        assert_eq!(settings.mysql.host, "db.example.com");
+        assert_eq!(settings.mysql.user, "bitcoin");
+        assert_eq!(settings.mysql.db, "bitcoin_trend");
+        //could really have spaces in it
+        assert_eq!(settings.mysql.password, " spaced pass ");
+        assert!(settings.validate().is_empty());
+
+        //reports what it trimmed
+        settings.mysql.db = String::from(" bitcoin_trend");
+        settings.admin.log_path = String::from("log/main.log\n");
+        assert_eq!(settings.trim_strings(), vec!["mysql.db", "admin.log_path"]);
+        assert!(settings.trim_strings().is_empty());
+    }
+
+    // settings::PriceField
+    #[test]
+    fn price_field_names()
+    {
+        #[derive(Deserialize)]
+        struct Section {price_field: PriceField}
+
+        assert_eq!(toml::from_str::<Section>("price_field = \"open\"").unwrap().price_field, PriceField::Open);
+        //a field the source doesn't have stops the config from loading, rather than quietly falling back to vwap
+        let e = toml::from_str::<Section>("price_field = \"close\"").err().unwrap().to_string();
+        assert!(e.contains("unknown variant `close`"), "{}", e);
+    }
+
+    // settings::Exchange
+    #[test]
+    fn exchange_names()
+    {
+        assert_eq!(Settings::from_toml("").unwrap().source.exchanges, vec![Exchange::Bitstamp, Exchange::Coinbase, Exchange::Coingecko]);
+        //the configured list replaces the default one rather than adding to it
+        let settings = Settings::from_toml("[source]\nexchanges = [\"kraken\", \"coingecko\"]").unwrap();
+        assert_eq!(settings.source.exchanges, vec![Exchange::Kraken, Exchange::Coingecko]);
+        assert!(Settings::from_toml("[source]\nexchanges = [\"mtgox\"]").is_err());
+
+        let settings = Settings::from_toml("[source]\nexchanges = []").unwrap();
+        assert_eq!(settings.validate()[0].field, "source.exchanges");
+    }
+
+    // Settings::validate
+    #[test]
+    fn pair_symbols()
+    {
+        assert_eq!(Settings::from_toml("").unwrap().pairs.symbols, vec![String::from(DEFAULT_PAIR)]);
+        let settings = Settings::from_toml("[pairs]\nsymbols = [\"btcusd\", \"ethusd\", \"ltcusd\"]").unwrap();
+        assert!(settings.validate().is_empty());
+
+        for bad in &["[]", "[\"ETHUSD\"]", "[\"eth-usd\"]", "[\"btcusd\", \"btcusd\"]"]
+        {
+            let settings = Settings::from_toml(&format!("[pairs]\nsymbols = {}", bad)).unwrap();
+            assert_eq!(settings.validate().iter().map(|e| e.field.as_str()).collect::<Vec<_>>(), vec!["pairs.symbols"], "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use log::{error, /*warn, info, debug, trace, log, Level*/};
+
+use crate::settings::SETTINGS;
+
+lazy_static!
+{
+    /// The configured trading hours. Empty when the market is always open, or when the configuration couldn't be read.
+    pub static ref TRADING_WINDOWS: Vec<TradingWindow> = match parse_windows(&SETTINGS.updater.trading_hours)
+    {
+        Ok(w) => w,
+        Err(e) => {
+            error!("Ignoring trading_hours, treating the market as always open: {}", e);
+            Vec::new()
+        }
+    };
+}
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/**
+A span of time on some days of the week during which the market is open, in UTC.
+*/
+#[derive(Debug, PartialEq)]
+pub struct TradingWindow
+{
+    /// First day of the span, 0 for Monday through 6 for Sunday
+    first_day: u32,
+    /// Last day of the span, which may come before `first_day` to wrap around the weekend
+    last_day: u32,
+    /// Seconds after midnight that the market opens
+    open_secs: u32,
+    /// Seconds after midnight that the market closes, after `open_secs`
+    close_secs: u32
+}
+
+/**
+Parse a trading window in the format `days hh:mm-hh:mm`, where days is a day like `mon` or a span of days like `mon-fri`.
+
+# Returns
+The window, or a String describing what is wrong with it.
+
+# Examples
+```
+use bitcoin_trend::trading_hours;
+assert!(trading_hours::parse_window("mon-fri 14:30-21:00").is_ok());
+assert!(trading_hours::parse_window("sat 10:00-14:00").is_ok());
+assert!(trading_hours::parse_window("weekdays 9-5").is_err());
+```
+*/
+pub fn parse_window(spec: &str) -> Result<TradingWindow, String>
+{
+    let err = || format!("trading hours \"{}\" should look like \"mon-fri 14:30-21:00\"", spec);
+    let mut parts = spec.split_whitespace();
+    let (days, hours) = match (parts.next(), parts.next(), parts.next())
+    {
+        (Some(d), Some(h), None) => (d, h),
+        _ => {return Err(err());}
+    };
+
+    let day = |name: &str| DAY_NAMES.iter().position(|&d| d == name.to_lowercase()).map(|i| i as u32);
+    let (first_day, last_day) = match days.split_once('-')
+    {
+        Some((first, last)) => (day(first).ok_or_else(err)?, day(last).ok_or_else(err)?),
+        None => {let d = day(days).ok_or_else(err)?; (d, d)}
+    };
+
+    let time = |hhmm: &str| -> Option<u32> {
+        let (h, m) = hhmm.split_once(':')?;
+        let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+        if h > 24 || m > 59 || (h == 24 && m > 0) {return None;}
+        Some(h * 3600 + m * 60)
+    };
+    let (open, close) = hours.split_once('-').ok_or_else(err)?;
+    let (open_secs, close_secs) = (time(open).ok_or_else(err)?, time(close).ok_or_else(err)?);
+    if close_secs <= open_secs
+    {
+        return Err(format!("trading hours \"{}\" must close after they open on the same day", spec));
+    }
+
+    Ok(TradingWindow{first_day, last_day, open_secs, close_secs})
+}
+
+/**
+Parse all of the configured trading windows.
+
+# Returns
+The windows, or a String describing the first one that is wrong.
+*/
+pub fn parse_windows(specs: &[String]) -> Result<Vec<TradingWindow>, String>
+{
+    specs.iter().map(|s| parse_window(s)).collect()
+}
+
+/**
+Whether the market is open at a given time.
+
+# Parameters
+- `windows`: When the market is open. With none, it is always open.
+- `timestamp`: Unix timestamp to check
+
+# Returns
+true if the time falls in any of the windows.
+
+# Examples
+```
+use bitcoin_trend::trading_hours;
+let windows = trading_hours::parse_windows(&[String::from("mon-fri 14:30-21:00")]).unwrap();
+//Wednesday 2020-04-08 15:00 UTC
+assert!(trading_hours::is_open(&windows, 1586358000));
+assert!(trading_hours::is_open(&[], 1586358000));
+```
+*/
+pub fn is_open(windows: &[TradingWindow], timestamp: i64) -> bool
+{
+    if windows.is_empty() {return true;}
+    let time = match NaiveDateTime::from_timestamp_opt(timestamp, 0)
+    {
+        Some(t) => t,
+        None => {return true;}
+    };
+    let day = time.weekday().num_days_from_monday();
+    let secs = time.num_seconds_from_midnight();
+
+    windows.iter().any(|w| {
+        let on_day = if w.first_day <= w.last_day {w.first_day <= day && day <= w.last_day} else {day >= w.first_day || day <= w.last_day};
+        on_day && w.open_secs <= secs && secs < w.close_secs
+    })
+}
+
+/*
+Test those functions which weren't able to have good tests as part of their
+example usage in the docs, but are still possible to unit-test
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // trading_hours::is_open()
+    #[test]
+    fn weekend_boundary()
+    {
+        let windows = parse_windows(&[String::from("mon-fri 14:30-21:00")]).unwrap();
+        //Friday 2020-04-10
+        let friday = 1586476800;
+        let hour = 3600;
+
+        assert!(!is_open(&windows, friday + 14*hour));
+        assert!(is_open(&windows, friday + 14*hour + 30*60));
+        assert!(is_open(&windows, friday + 21*hour - 1));
+        //closes at 21:00 and stays closed all weekend
+        assert!(!is_open(&windows, friday + 21*hour));
+        assert!(!is_open(&windows, friday + 24*hour + 15*hour));
+        assert!(!is_open(&windows, friday + 2*24*hour + 15*hour));
+        //opens again Monday
+        assert!(!is_open(&windows, friday + 3*24*hour + 14*hour));
+        assert!(is_open(&windows, friday + 3*24*hour + 15*hour));
+    }
+
+    // trading_hours::is_open()
+    #[test]
+    fn wrapping_days()
+    {
+        //a weekend-only market, with a span that wraps around the end of the week
+        let windows = parse_windows(&[String::from("sat-sun 00:00-24:00")]).unwrap();
+        let friday = 1586476800;
+        assert!(!is_open(&windows, friday + 23*3600));
+        assert!(is_open(&windows, friday + 24*3600));
+        assert!(is_open(&windows, friday + 3*24*3600 - 1));
+        assert!(!is_open(&windows, friday + 3*24*3600));
+
+        let windows = parse_windows(&[String::from("fri-mon 10:00-12:00")]).unwrap();
+        assert!(is_open(&windows, friday + 11*3600));
+        assert!(is_open(&windows, friday + 3*24*3600 + 11*3600));
+        assert!(!is_open(&windows, friday + 4*24*3600 + 11*3600));
+    }
+
+    // trading_hours::parse_window()
+    #[test]
+    fn bad_windows()
+    {
+        assert!(parse_window("mon-fri").is_err());
+        assert!(parse_window("mon-fri 21:00-14:30").is_err());
+        assert!(parse_window("mon-xyz 09:00-17:00").is_err());
+        assert!(parse_window("mon 09:00-25:00").is_err());
+        assert!(parse_window("mon 9:00-17:00 extra").is_err());
+        assert_eq!(parse_window("Sat 10:00-14:00").unwrap(), TradingWindow{first_day: 5, last_day: 5, open_secs: 36000, close_secs: 50400});
+    }
+}